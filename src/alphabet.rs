@@ -0,0 +1,108 @@
+use bio::alphabets;
+use std::any::Any;
+use std::str::FromStr;
+
+use super::fastx;
+
+/// The residues sequences may be made of. Case is ignored, both when checking sequences and
+/// when comparing them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Alphabet {
+    Dna,
+    Protein,
+}
+
+impl FromStr for Alphabet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dna" => Ok(Alphabet::Dna),
+            "protein" => Ok(Alphabet::Protein),
+            _ => Err(format!("alphabet {} is not one of dna, protein", s)),
+        }
+    }
+}
+
+impl Alphabet {
+    /// IUPAC codes, including ambiguous bases and residues, and `*` for protein stop codons.
+    fn residues(&self) -> alphabets::Alphabet {
+        match self {
+            Alphabet::Dna => alphabets::dna::iupac_alphabet(),
+            Alphabet::Protein => alphabets::Alphabet::new(
+                &b"ACDEFGHIKLMNPQRSTVWYBJOUXZ*acdefghiklmnpqrstvwybjouxz"[..],
+            ),
+        }
+    }
+
+    pub fn check(&self, seq: &[u8]) -> Result<(), &'static str> {
+        if self.residues().is_word(seq) {
+            return Ok(());
+        }
+        match self {
+            Alphabet::Dna => Err("sequence has bases outside the DNA alphabet"),
+            Alphabet::Protein => Err("sequence has residues outside the protein alphabet"),
+        }
+    }
+}
+
+/// A record whose check also checks its sequence against an alphabet.
+pub struct AlphabetRecord {
+    record: Box<dyn fastx::Record>,
+    alphabet: Alphabet,
+}
+
+impl AlphabetRecord {
+    pub fn new(record: Box<dyn fastx::Record>, alphabet: Alphabet) -> Self {
+        AlphabetRecord { record, alphabet }
+    }
+}
+
+impl fastx::Record for AlphabetRecord {
+    fn id(&self) -> &str {
+        self.record.id()
+    }
+
+    fn desc(&self) -> Option<&str> {
+        self.record.desc()
+    }
+
+    fn seq(&self) -> &[u8] {
+        self.record.seq()
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        self.record.qual()
+    }
+
+    fn check(&self) -> Result<(), &str> {
+        self.record.check()?;
+        self.alphabet.check(self.record.seq())
+    }
+
+    // writers see the wrapped record
+    fn as_any(&self) -> &dyn Any {
+        self.record.as_any()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bio::io::fasta;
+    use fastx::Record;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(Alphabet::Protein.check(b"MKVLaX*"), Ok(()));
+        assert!(Alphabet::Dna.check(b"MKVL").is_err());
+        assert_eq!(Alphabet::Dna.check(b"ACGTNacgtn"), Ok(()));
+
+        let record = fasta::Record::with_attrs("id_a", None, b"MKV#");
+        let record = AlphabetRecord::new(Box::new(record), Alphabet::Protein);
+        assert_eq!(
+            record.check(),
+            Err("sequence has residues outside the protein alphabet")
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use core::hash::Hash;
+use core::hash::Hasher;
+use std::any::Any;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+
+use super::fastx;
+use super::window::KeyWindow;
+
+// keys are split into this many segments, a read matches a representative only if they share
+// every segment the read has no N in
+const SEGMENTS: usize = 4;
+
+fn is_n(base: &u8) -> bool {
+    *base == b'N' || *base == b'n'
+}
+
+pub fn has_n(key: &[u8]) -> bool {
+    key.iter().any(is_n)
+}
+
+/// Whether every base of `read` is an N or the base of `representative` at the same position.
+fn matches(read: &[u8], representative: &[u8]) -> bool {
+    read.len() == representative.len()
+        && read
+            .iter()
+            .zip(representative)
+            .all(|(base, representative_base)| is_n(base) || base == representative_base)
+}
+
+fn segment(key: &[u8], i: usize) -> &[u8] {
+    let size = key.len().div_ceil(SEGMENTS);
+    let start = cmp::min(i * size, key.len());
+    let end = cmp::min(start + size, key.len());
+    &key[start..end]
+}
+
+fn hash_segment(length: usize, i: usize, segment: &[u8]) -> u64 {
+    let mut segment_hasher = DefaultHasher::new();
+    (length, i).hash(&mut segment_hasher);
+    Hash::hash_slice(segment, &mut segment_hasher);
+    segment_hasher.finish()
+}
+
+/// Finds the first representative key a read with N calls matches. Representatives are indexed
+/// by each of their segments, so only representatives sharing whichever segment the read has no
+/// N in is shared by the fewest are compared, or every representative of the same length if each
+/// segment has an N.
+#[derive(Default)]
+pub struct WildcardIndex {
+    keys: Vec<(Vec<u8>, u64)>,
+    by_segment: HashMap<u64, Vec<usize>>,
+    by_length: HashMap<usize, Vec<usize>>,
+}
+
+impl WildcardIndex {
+    /// `value` is returned when a read matches `key`.
+    pub fn insert(&mut self, key: Vec<u8>, value: u64) {
+        let key_index = self.keys.len();
+        for i in 0..SEGMENTS {
+            self.by_segment
+                .entry(hash_segment(key.len(), i, segment(&key, i)))
+                .or_default()
+                .push(key_index);
+        }
+        self.by_length.entry(key.len()).or_default().push(key_index);
+        self.keys.push((key, value));
+    }
+
+    pub fn find(&self, read: &[u8]) -> Option<u64> {
+        // a match shares every segment the read has no N in, so the fewest candidates will do
+        let segment_candidates: Option<Vec<&Vec<usize>>> = (0..SEGMENTS)
+            .filter(|i| !has_n(segment(read, *i)))
+            .map(|i| {
+                self.by_segment
+                    .get(&hash_segment(read.len(), i, segment(read, i)))
+            })
+            .collect();
+        let candidates = match segment_candidates?
+            .into_iter()
+            .min_by_key(|candidates| candidates.len())
+        {
+            Some(candidates) => candidates,
+            None => self.by_length.get(&read.len())?,
+        };
+        // candidates are in insertion order
+        candidates
+            .iter()
+            .map(|key_index| &self.keys[*key_index])
+            .find(|(key, _)| matches(read, key))
+            .map(|(_, value)| *value)
+    }
+}
+
+/// A record whose check also fails if the key of its sequence has more than a fraction of N
+/// calls.
+pub struct NFilterRecord {
+    record: Box<dyn fastx::Record>,
+    problem_opt: Option<String>,
+}
+
+impl NFilterRecord {
+    pub fn new(
+        record: Box<dyn fastx::Record>,
+        key_window: &KeyWindow,
+        max_n_fraction: f64,
+    ) -> Self {
+        let key = key_window.extract(record.seq());
+        let n_count = key.iter().filter(|base| is_n(base)).count();
+        let problem_opt = if n_count as f64 > max_n_fraction * key.len() as f64 {
+            Some(format!(
+                "key has {} Ns in {} bases, more than the maximum fraction {}",
+                n_count,
+                key.len(),
+                max_n_fraction
+            ))
+        } else {
+            None
+        };
+        NFilterRecord {
+            record,
+            problem_opt,
+        }
+    }
+}
+
+impl fastx::Record for NFilterRecord {
+    fn id(&self) -> &str {
+        self.record.id()
+    }
+
+    fn desc(&self) -> Option<&str> {
+        self.record.desc()
+    }
+
+    fn seq(&self) -> &[u8] {
+        self.record.seq()
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        self.record.qual()
+    }
+
+    fn check(&self) -> Result<(), &str> {
+        self.record.check()?;
+        match self.problem_opt.as_ref() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    // writers see the wrapped record
+    fn as_any(&self) -> &dyn Any {
+        self.record.as_any()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bio::io::fasta;
+    use fastx::Record;
+
+    #[test]
+    fn test_wildcard_index() {
+        let mut index = WildcardIndex::default();
+        index.insert(b"ACGTACGTAC".to_vec(), 1);
+        index.insert(b"ACGTACGTAA".to_vec(), 2);
+        index.insert(b"TTGTACGTAA".to_vec(), 3);
+        assert_eq!(index.find(b"ACGTACGTAN"), Some(1));
+        assert_eq!(index.find(b"NNGTACGTAA"), Some(2));
+        assert_eq!(index.find(b"TTNNNNNNNN"), Some(3));
+        assert_eq!(index.find(b"NTNNNNNNNN"), Some(3));
+        assert_eq!(index.find(b"GGGTACGTAN"), None);
+        assert_eq!(index.find(b"ACGTACGTN"), None);
+        // the first segment is shared by every key, the last picks out the match
+        assert_eq!(index.find(b"ACGNNNNTAA"), Some(2));
+        assert_eq!(index.find(b"ACGNNNNTTT"), None);
+    }
+
+    #[test]
+    fn test_n_filter_record() {
+        let record = fasta::Record::with_attrs("id_a", None, b"ACGNNTTTTT");
+        let record = NFilterRecord::new(Box::new(record), &KeyWindow::prefix(Some(5)), 0.2);
+        assert_eq!(
+            record.check(),
+            Err("key has 2 Ns in 5 bases, more than the maximum fraction 0.2")
+        );
+        let record = fasta::Record::with_attrs("id_a", None, b"ACGTNTTTTT");
+        assert_eq!(
+            NFilterRecord::new(Box::new(record), &KeyWindow::prefix(None), 0.1).check(),
+            Ok(())
+        );
+    }
+}
@@ -1,85 +1,640 @@
 use core::hash::Hash;
 use core::hash::Hasher;
-use std::cmp;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 
+use super::ambiguity::{self, WildcardIndex};
 use super::fastx;
+use super::group::RecordGroup;
+use super::invalid::InvalidRecords;
+use super::key::{KeyExtractor, MateWindows, WithSequence};
+use super::membership::{Columns, Membership, MembershipSink, Outcome, TableSink};
 use super::paired::PairedRecord;
+use super::read_structure::ReadStructure;
+use super::representative::{Candidate, Measure, RepresentativePolicy};
+use super::rotation::{self, Rotation, Strands};
+use super::table::{Field, TableFormat, TableWriter};
+use super::window::KeyWindow;
 
 pub struct Cluster {
     id: String,
     size: u64,
+    representative_index: u64,
+    // how the representative turns into the canonical rotation of circular sequences
+    rotation_opt: Option<Rotation>,
+    // of the representative, only measured if there is a representative policy
+    measure: Measure,
 }
 
-pub struct Clusters<T: io::Write> {
-    cluster_map: HashMap<u64, Cluster>,
-    cluster_order: Vec<u64>,
-    cluster_csv_writer: Option<csv::Writer<T>>,
+impl Cluster {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The position of the representative among all inserted records.
+    pub fn representative_index(&self) -> u64 {
+        self.representative_index
+    }
+}
+
+/// What a record is clustered on.
+struct Key {
+    hash: u64,
+    barcode_opt: Option<Vec<u8>>,
+    rotation_opt: Option<Rotation>,
+    // the group_key of the members, kept to index representatives if N is a wildcard
+    wildcard_key_opt: Option<Vec<u8>>,
+    // the members with a key window, recorded if mates have their own windows
+    key_mates_opt: Option<Vec<usize>>,
+}
+
+impl Key {
+    fn hashed<K: Hash + ?Sized>(key: &K) -> Self {
+        let mut key_hasher = DefaultHasher::new();
+        key.hash(&mut key_hasher);
+        Key {
+            hash: key_hasher.finish(),
+            barcode_opt: None,
+            rotation_opt: None,
+            wildcard_key_opt: None,
+            key_mates_opt: None,
+        }
+    }
+}
+
+/// Keys members the way `Clusters` compares them: upper cased if case is ignored and turned to
+/// their canonical rotation if circular, so `group_key` frames exactly what is clustered on.
+struct MemberKeys<'b> {
+    key_extractor: &'b dyn KeyExtractor,
+    fold_case: bool,
+    circular_opt: Option<Strands>,
+    // how the first keyed member turns into its canonical rotation
+    rotation_opt: Cell<Option<Rotation>>,
+}
+
+impl KeyExtractor for MemberKeys<'_> {
+    fn extract_key(&self, member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        let start = key.len();
+        self.key_extractor.extract_key(member, record, key);
+        if self.fold_case {
+            key[start..].make_ascii_uppercase();
+        }
+        if let Some(strands) = self.circular_opt {
+            let (canonical, rotation) = rotation::canonical(&key[start..], strands);
+            key.truncate(start);
+            key.extend_from_slice(&canonical);
+            if self.rotation_opt.get().is_none() {
+                self.rotation_opt.set(Some(rotation));
+            }
+        }
+    }
+
+    fn ignores(&self, member: usize) -> bool {
+        self.key_extractor.ignores(member)
+    }
+}
+
+/// The representative a caller picked for a record.
+struct Assigned<'b> {
+    representative_id: &'b str,
+    // whether the record is the representative itself
+    representative: bool,
+}
+
+/// A member waiting to be sent to the membership sink.
+struct Member {
+    cluster_index: usize,
+    id: String,
+    rotation_opt: Option<Rotation>,
+    key_mates_opt: Option<Vec<usize>>,
+}
+
+/// The cluster of every inserted record by its index, and if members are retained its read ID.
+#[derive(Default)]
+struct Assignments {
+    cluster_indices: Vec<usize>,
+    retain_ids: bool,
+    ids: Vec<String>,
+    // a read ID inserted more than once maps to its last record
+    record_of_id: HashMap<String, usize>,
+    // the records of each cluster in the order they were inserted, which stay in their cluster
+    // when a representative policy picks another representative
+    cluster_members: Vec<Vec<usize>>,
+}
+
+#[derive(Default)]
+pub struct CellStats {
     total_records: u64,
-    prefix_length_opt: Option<usize>,
+    unique_records: u64,
 }
 
-impl<T: std::io::Write> Clusters<T> {
-    fn insert_record(&mut self, seq_hash: u64, id: String) -> Result<bool, csv::Error> {
+pub struct Clusters<'a> {
+    cluster_map: HashMap<u64, usize>,
+    clusters: Vec<Cluster>,
+    assignments_opt: Option<Assignments>,
+    sink_opt: Option<Box<dyn MembershipSink + 'a>>,
+    // the columns depend on the options, so the sink begins once they are all set
+    sink_begun: bool,
+    total_records: u64,
+    key_extractor: Box<dyn KeyExtractor>,
+    // whether the cluster output lists the members each key was made of
+    record_key_mates: bool,
+    fold_case: bool,
+    circular_opt: Option<Strands>,
+    wildcard_index_opt: Option<WildcardIndex>,
+    read_structures_opt: Option<Vec<ReadStructure>>,
+    cell_stats: HashMap<Vec<u8>, CellStats>,
+    cell_order: Vec<Vec<u8>>,
+    invalid_records: InvalidRecords,
+    policy_opt: Option<Box<dyn RepresentativePolicy>>,
+    // representatives may change until every record is inserted, so with a policy members are
+    // sent to the sink at the end
+    deferred_members: Vec<Member>,
+}
+
+impl<'a> Clusters<'a> {
+    /// A new cluster is named after the representative of `assigned_opt`, defaulting to the
+    /// record itself. `measure_opt` is only needed by representative policies.
+    fn insert_record(
+        &mut self,
+        key: Key,
+        id: String,
+        assigned_opt: Option<Assigned>,
+        measure_opt: Option<Measure>,
+    ) -> io::Result<Outcome> {
         self.total_records += 1;
-        match self.cluster_map.get_mut(&seq_hash) {
-            Some(mut cluster) => {
+        let is_new = !self.cluster_map.contains_key(&key.hash);
+        if let Some(barcode) = key.barcode_opt {
+            let cell_order = &mut self.cell_order;
+            let cell_stats = self
+                .cell_stats
+                .entry(barcode)
+                .or_insert_with_key(|barcode| {
+                    cell_order.push(barcode.clone());
+                    CellStats::default()
+                });
+            cell_stats.total_records += 1;
+            if is_new {
+                cell_stats.unique_records += 1;
+            }
+        }
+
+        let (cluster_index, outcome) = match self.cluster_map.get(&key.hash) {
+            Some(cluster_index) => {
+                let cluster = &mut self.clusters[*cluster_index];
                 cluster.size += 1;
-                self.cluster_csv_writer
-                    .as_mut()
-                    .map(|cluster_csv_writer| {
-                        cluster_csv_writer
-                            .write_record(vec![&cluster.id, &id])
-                            .map(|_| false)
-                    })
-                    .unwrap_or(Ok(false))
+                let mut outcome = Outcome::Duplicate {
+                    representative_id: cluster.id.clone(),
+                };
+                // the representative may come after other members of its cluster
+                if let Some(Assigned {
+                    representative: true,
+                    ..
+                }) = assigned_opt
+                {
+                    cluster.representative_index = self.total_records - 1;
+                    outcome = Outcome::Representative { replaced_opt: None };
+                }
+                if let (Some(policy), Some(measure)) = (self.policy_opt.as_ref(), measure_opt) {
+                    let candidate = Candidate {
+                        id: &id,
+                        index: self.total_records - 1,
+                        measure,
+                    };
+                    let current = Candidate {
+                        id: &cluster.id,
+                        index: cluster.representative_index,
+                        measure: cluster.measure,
+                    };
+                    if policy.replaces(&candidate, &current) {
+                        let replaced = std::mem::replace(&mut cluster.id, id.clone());
+                        cluster.representative_index = self.total_records - 1;
+                        cluster.rotation_opt = key.rotation_opt;
+                        cluster.measure = measure;
+                        outcome = Outcome::Representative {
+                            replaced_opt: Some(replaced),
+                        };
+                    }
+                }
+                (*cluster_index, outcome)
             }
             None => {
-                let res_opt = self.cluster_csv_writer.as_mut().map(|cluster_csv_writer| {
-                    cluster_csv_writer
-                        .write_record(vec![&id, &id])
-                        .map(|_| true)
+                let cluster_id = assigned_opt
+                    .as_ref()
+                    .map(|assigned| assigned.representative_id.to_owned())
+                    .unwrap_or_else(|| id.clone());
+                self.clusters.push(Cluster {
+                    id: cluster_id,
+                    size: 1,
+                    representative_index: self.total_records - 1,
+                    rotation_opt: key.rotation_opt,
+                    measure: measure_opt.unwrap_or_default(),
                 });
-                self.cluster_map.insert(seq_hash, Cluster { id, size: 1 });
-                self.cluster_order.push(seq_hash);
-                res_opt.unwrap_or(Ok(true))
+                self.cluster_map.insert(key.hash, self.clusters.len() - 1);
+                if let (Some(wildcard_index), Some(wildcard_key)) =
+                    (self.wildcard_index_opt.as_mut(), key.wildcard_key_opt)
+                {
+                    wildcard_index.insert(wildcard_key, key.hash);
+                }
+                let outcome = match assigned_opt {
+                    Some(assigned) if !assigned.representative => Outcome::Duplicate {
+                        representative_id: assigned.representative_id.to_owned(),
+                    },
+                    _ => Outcome::Representative { replaced_opt: None },
+                };
+                (self.clusters.len() - 1, outcome)
+            }
+        };
+        if let Some(assignments) = self.assignments_opt.as_mut() {
+            if assignments.retain_ids {
+                let record_index = assignments.cluster_indices.len();
+                assignments.record_of_id.insert(id.clone(), record_index);
+                assignments.ids.push(id.clone());
+                if cluster_index == assignments.cluster_members.len() {
+                    assignments.cluster_members.push(Vec::new());
+                }
+                assignments.cluster_members[cluster_index].push(record_index);
             }
+            assignments.cluster_indices.push(cluster_index);
+        }
+        let member = Member {
+            cluster_index,
+            id,
+            rotation_opt: key.rotation_opt,
+            key_mates_opt: key.key_mates_opt,
+        };
+        if self.policy_opt.is_some() {
+            self.deferred_members.push(member);
+        } else {
+            self.send_member(&member)?;
+        }
+        Ok(outcome)
+    }
+
+    fn begin_sink(&mut self) -> io::Result<()> {
+        if self.sink_begun {
+            return Ok(());
+        }
+        if let Some(sink) = self.sink_opt.as_mut() {
+            sink.begin(&Columns {
+                rotation: self.circular_opt.is_some(),
+                key_mates: self.record_key_mates,
+            })?;
+        }
+        self.sink_begun = true;
+        Ok(())
+    }
+
+    /// Circular members are sent with how they turn into their representative.
+    fn send_member(&mut self, member: &Member) -> io::Result<()> {
+        self.begin_sink()?;
+        if let Some(sink) = self.sink_opt.as_mut() {
+            let cluster = &self.clusters[member.cluster_index];
+            let rotation_opt = member.rotation_opt.zip(cluster.rotation_opt).map(
+                |(rotation, representative_rotation)| {
+                    rotation.relative_to(&representative_rotation)
+                },
+            );
+            sink.member(&Membership {
+                representative_id: &cluster.id,
+                id: &member.id,
+                rotation_opt,
+                key_mates_opt: member.key_mates_opt.as_deref(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Sends the members held back for a representative policy then finishes the sink, which
+    /// begins it if no record was inserted.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.begin_sink()?;
+        for member in std::mem::take(&mut self.deferred_members) {
+            self.send_member(&member)?;
+        }
+        if let Some(sink) = self.sink_opt.as_mut() {
+            sink.finish()?;
         }
+        Ok(())
+    }
+
+    fn measure(&self, members: &[&dyn fastx::Record]) -> Option<Measure> {
+        self.policy_opt.as_ref().map(|_| Measure::of(members))
     }
 
-    fn get_prefix<'a, 'b>(&'a self, seq: &'b [u8]) -> &'b [u8] {
-        let seq_length = seq.len();
-        let prefix_length = self
-            .prefix_length_opt
-            .map(|prefix_length| cmp::min(prefix_length, seq_length))
-            .unwrap_or(seq_length);
-        &seq[..prefix_length]
+    /// The key extractor with case folding and circular rotation applied to each member.
+    fn member_keys(&self) -> MemberKeys<'_> {
+        MemberKeys {
+            key_extractor: self.key_extractor.as_ref(),
+            fold_case: self.fold_case,
+            circular_opt: self.circular_opt,
+            rotation_opt: Cell::new(None),
+        }
     }
 
-    pub fn insert_single<R: fastx::Record>(&mut self, record: &R) -> Result<bool, csv::Error> {
+    /// Hashes the `group_key` of the given members, keeping the cell barcode if read structures
+    /// are set. With read structures the key is the barcode, then the UMI, then the `group_key` of
+    /// the templates. Circular sequences are keyed on their canonical rotation. If N is
+    /// a wildcard, keys with an N that aren't already a cluster take the hash of the first
+    /// representative they match. Members the key extractor ignores are left out entirely.
+    fn hash_members<'b, I: Iterator<Item = (usize, &'b dyn fastx::Record)>>(
+        &self,
+        members: I,
+    ) -> Key {
+        let members: Vec<(usize, &dyn fastx::Record)> = members
+            .filter(|(member, _)| !self.key_extractor.ignores(*member))
+            .collect();
+        let key_mates_opt = if self.record_key_mates {
+            Some(members.iter().map(|(member, _)| *member).collect())
+        } else {
+            None
+        };
         let mut seq_hasher = DefaultHasher::new();
-        Hash::hash_slice(self.get_prefix(record.seq()), &mut seq_hasher);
-        let seq_hash = seq_hasher.finish();
-        self.insert_record(seq_hash, record.id().to_owned())
+        match self.read_structures_opt.as_ref() {
+            None => {
+                let member_keys = self.member_keys();
+                let key = member_keys.group_key(&members);
+                Hash::hash_slice(&key, &mut seq_hasher);
+                let mut hash = seq_hasher.finish();
+                let wildcard_key_opt = self.wildcard_index_opt.as_ref().map(|wildcard_index| {
+                    if !self.cluster_map.contains_key(&hash) && ambiguity::has_n(&key) {
+                        hash = wildcard_index.find(&key).unwrap_or(hash);
+                    }
+                    key
+                });
+                Key {
+                    hash,
+                    barcode_opt: None,
+                    rotation_opt: member_keys.rotation_opt.get(),
+                    wildcard_key_opt,
+                    key_mates_opt,
+                }
+            }
+            Some(read_structures) => {
+                let mut barcode = Vec::new();
+                let mut umi = Vec::new();
+                let mut templates = Vec::new();
+                for (member, record) in members {
+                    let segments = read_structures[member].extract(record.seq());
+                    barcode.extend(segments.barcode);
+                    umi.extend(segments.umi);
+                    templates.push((
+                        member,
+                        WithSequence::new(record, Cow::Owned(segments.template)),
+                    ));
+                }
+                let templates: Vec<(usize, &dyn fastx::Record)> = templates
+                    .iter()
+                    .map(|(member, template)| (*member, template as &dyn fastx::Record))
+                    .collect();
+                Hash::hash_slice(&barcode, &mut seq_hasher);
+                Hash::hash(&0, &mut seq_hasher);
+                Hash::hash_slice(&umi, &mut seq_hasher);
+                Hash::hash(&0, &mut seq_hasher);
+                let member_keys = MemberKeys {
+                    circular_opt: None,
+                    ..self.member_keys()
+                };
+                Hash::hash_slice(&member_keys.group_key(&templates), &mut seq_hasher);
+                Key {
+                    hash: seq_hasher.finish(),
+                    barcode_opt: Some(barcode),
+                    rotation_opt: None,
+                    wildcard_key_opt: None,
+                    key_mates_opt,
+                }
+            }
+        }
+    }
+
+    pub fn insert_single<R: fastx::Record>(&mut self, record: &R) -> io::Result<Outcome> {
+        let key = self.hash_members(vec![(0, record as &dyn fastx::Record)].into_iter());
+        let measure_opt = self.measure(&[record]);
+        self.insert_record(key, record.id().to_owned(), None, measure_opt)
+    }
+
+    /// Keys a mate without its pair with the key window of its position in the pair.
+    pub fn insert_orphan<R: fastx::Record>(
+        &mut self,
+        record: &R,
+        member: usize,
+    ) -> io::Result<Outcome> {
+        let key = self.hash_members(vec![(member, record as &dyn fastx::Record)].into_iter());
+        let measure_opt = self.measure(&[record]);
+        self.insert_record(key, record.id().to_owned(), None, measure_opt)
     }
 
     pub fn insert_pair<R: fastx::Record>(
         &mut self,
         record: &PairedRecord<R>,
-    ) -> Result<bool, csv::Error> {
-        let mut seq_hasher = DefaultHasher::new();
-        Hash::hash_slice(self.get_prefix(record.r1().seq()), &mut seq_hasher);
-        Hash::hash(&0, &mut seq_hasher);
-        Hash::hash_slice(self.get_prefix(record.r2().seq()), &mut seq_hasher);
-        let seq_hash = seq_hasher.finish();
-        self.insert_record(seq_hash, record.id().to_owned())
+    ) -> io::Result<Outcome> {
+        let members: Vec<(usize, &dyn fastx::Record)> = vec![(0, record.r1()), (1, record.r2())];
+        let key = self.hash_members(members.into_iter());
+        let measure_opt = self.measure(&[record.r1(), record.r2()]);
+        self.insert_record(key, record.id().to_owned(), None, measure_opt)
+    }
+
+    /// Only the members at `key_members` make up the key, in the order given. Keying on r1 and r2
+    /// of a group matches `insert_pair`.
+    pub fn insert_group<R: fastx::Record>(
+        &mut self,
+        record: &RecordGroup<R>,
+        key_members: &[usize],
+    ) -> io::Result<Outcome> {
+        let members = key_members
+            .iter()
+            .map(|member| (*member, &record.members()[*member] as &dyn fastx::Record));
+        let key = self.hash_members(members);
+        let all_members: Vec<&dyn fastx::Record> = record
+            .members()
+            .iter()
+            .map(|member| member as &dyn fastx::Record)
+            .collect();
+        let measure_opt = self.measure(&all_members);
+        self.insert_record(key, record.id().to_owned(), None, measure_opt)
+    }
+
+    /// Clusters on an alignment position key rather than on sequence. Representatives are the
+    /// first of their cluster whatever the policy.
+    pub fn insert_position<K: Hash>(&mut self, key: &K, id: &str) -> io::Result<Outcome> {
+        self.insert_record(Key::hashed(key), id.to_owned(), None, None)
+    }
+
+    /// Clusters a record with a representative picked by the caller, which may come later in the
+    /// input. Records are told apart by their index among the caller's records rather than by
+    /// read ID, which need not be unique.
+    pub fn insert_assigned(
+        &mut self,
+        id: &str,
+        index: usize,
+        representative_id: &str,
+        representative_index: usize,
+    ) -> io::Result<Outcome> {
+        let assigned = Assigned {
+            representative_id,
+            representative: index == representative_index,
+        };
+        self.insert_record(
+            Key::hashed(&representative_index),
+            id.to_owned(),
+            Some(assigned),
+            None,
+        )
+    }
+
+    /// Read structures are indexed by member, so pairs and groups need one per input.
+    pub fn with_read_structures(mut self, read_structures: Vec<ReadStructure>) -> Self {
+        self.read_structures_opt = Some(read_structures);
+        self
+    }
+
+    /// Replaces the prefix given on creation. With a single window every member is keyed on it,
+    /// otherwise windows are indexed by member and the cluster output lists the members each key
+    /// was made of.
+    pub fn with_key_windows(mut self, key_windows: Vec<KeyWindow>) -> Self {
+        self.record_key_mates = key_windows.len() > 1;
+        self.key_extractor = Box::new(MateWindows(key_windows));
+        self
+    }
+
+    /// Lets the policy replace the representative of a cluster as members arrive. Duplicates are
+    /// only known once every record is inserted, so the cluster output is written by `finish`.
+    pub fn with_representative_policy(mut self, policy: Box<dyn RepresentativePolicy>) -> Self {
+        self.policy_opt = Some(policy);
+        self
+    }
+
+    /// Replaces the key windows with another way of keying members.
+    pub fn with_key_extractor(mut self, key_extractor: Box<dyn KeyExtractor>) -> Self {
+        self.key_extractor = key_extractor;
+        self
+    }
+
+    /// Sequences are circular, so rotations of a sequence, and with `Strands::Both` its reverse
+    /// complement, are duplicates.
+    pub fn with_circular(mut self, strands: Strands) -> Self {
+        self.circular_opt = Some(strands);
+        self
+    }
+
+    /// An N in a read matches any base of an existing representative, the first one it matches
+    /// if there are several. Representatives are not merged, so an N in a representative only
+    /// matches an N.
+    pub fn with_n_wildcard(mut self) -> Self {
+        self.wildcard_index_opt = Some(WildcardIndex::default());
+        self
+    }
+
+    /// Sequences differing only in case are duplicates.
+    pub fn with_case_folding(mut self) -> Self {
+        self.fold_case = true;
+        self
+    }
+
+    pub fn with_invalid_records(mut self, invalid_records: InvalidRecords) -> Self {
+        self.invalid_records = invalid_records;
+        self
+    }
+
+    /// Records a record that failed validation instead of inserting it. `record_number` is
+    /// 1-based.
+    pub fn reject(&mut self, record_number: u64, id: &str, reason: &str) -> Result<(), String> {
+        self.invalid_records.reject(record_number, id, reason)
+    }
+
+    pub fn invalid_records(&self) -> u64 {
+        self.invalid_records.count()
+    }
+
+    pub fn cells(&self) -> u64 {
+        self.cell_order.len() as u64
+    }
+
+    pub fn write_cell_stats<R: std::io::Write>(
+        &self,
+        table_writer: &mut TableWriter<R>,
+    ) -> io::Result<()> {
+        table_writer.write_header(&[
+            "cell barcode",
+            "total reads",
+            "unique reads",
+            "duplicate reads",
+        ])?;
+        for barcode in self.cell_order.iter() {
+            // guaranteed to be present
+            let cell_stats = self.cell_stats.get(barcode).unwrap();
+            table_writer.write_row(&[
+                Field::Text(String::from_utf8_lossy(barcode)),
+                Field::Number(cell_stats.total_records),
+                Field::Number(cell_stats.unique_records),
+                Field::Number(cell_stats.total_records - cell_stats.unique_records),
+            ])?;
+        }
+        table_writer.flush()
+    }
+
+    /// Keeps the cluster of every inserted record so it can be looked up with
+    /// `cluster_of_record`.
+    pub fn retain_assignments(mut self) -> Self {
+        self.assignments_opt
+            .get_or_insert_with(Assignments::default);
+        self
+    }
+
+    /// Only available if assignments are retained.
+    pub fn cluster_of_record(&self, record_index: u64) -> Option<&Cluster> {
+        self.assignments_opt
+            .as_ref()
+            .and_then(|assignments| assignments.cluster_indices.get(record_index as usize))
+            .map(|cluster_index| &self.clusters[*cluster_index])
+    }
+
+    /// Also keeps the read ID of every inserted record so clusters can be looked up with
+    /// `cluster_of` and `members`.
+    pub fn retain_members(mut self) -> Self {
+        self.assignments_opt
+            .get_or_insert_with(Assignments::default)
+            .retain_ids = true;
+        self
+    }
+
+    /// The cluster of the record with `read_id`. Only available if members are retained.
+    pub fn cluster_of(&self, read_id: &str) -> Option<&Cluster> {
+        let assignments = self.assignments_opt.as_ref()?;
+        let record_index = *assignments.record_of_id.get(read_id)?;
+        Some(&self.clusters[assignments.cluster_indices[record_index]])
+    }
+
+    /// The read IDs of the members of the cluster represented by `representative_id`, in the order
+    /// they were inserted. Only available if members are retained.
+    pub fn members(&self, representative_id: &str) -> Option<impl Iterator<Item = &str>> {
+        let assignments = self.assignments_opt.as_ref()?;
+        let record_index = *assignments.record_of_id.get(representative_id)?;
+        let cluster_index = assignments.cluster_indices[record_index];
+        if self.clusters[cluster_index].id != representative_id {
+            return None;
+        }
+        let members = assignments.cluster_members[cluster_index]
+            .iter()
+            .map(move |record_index| assignments.ids[*record_index].as_str());
+        Some(members)
+    }
+
+    /// Every cluster in the order it was found.
+    pub fn clusters(&self) -> impl Iterator<Item = &Cluster> {
+        self.clusters.iter()
     }
 
     pub fn unique_records(&self) -> u64 {
-        self.cluster_map.len() as u64
+        self.clusters.len() as u64
     }
 
     pub fn duplicate_records(&self) -> u64 {
@@ -92,54 +647,70 @@ impl<T: std::io::Write> Clusters<T> {
 
     pub fn write_sizes<R: std::io::Write>(
         &self,
-        csv_writer: &mut csv::Writer<R>,
-    ) -> Result<(), csv::Error> {
-        csv_writer.write_record(vec!["representative read id", "cluster size"])?;
-        for cluster_hash in self.cluster_order.iter() {
-            // guaranteed to be present
-            let cluster = self.cluster_map.get(cluster_hash).unwrap();
-            csv_writer.write_record(vec![&cluster.id, &cluster.size.to_string()])?;
+        table_writer: &mut TableWriter<R>,
+    ) -> io::Result<()> {
+        table_writer.write_header(&["representative read id", "cluster size"])?;
+        for cluster in self.clusters() {
+            table_writer.write_row(&[Field::from(cluster.id()), Field::Number(cluster.size)])?;
         }
-        Ok(())
+        table_writer.flush()
     }
 
-    pub fn from_writer(
-        cluster_output_opt: Option<T>,
-        prefix_length_opt: Option<usize>,
-        capacity: usize,
-    ) -> Result<Self, csv::Error> {
-        let cluster_csv_writer_opt = cluster_output_opt.map(csv::Writer::from_writer);
+    /// Sends membership to `sink`, instead of any cluster output set earlier.
+    pub fn with_membership_sink(mut self, sink: Box<dyn MembershipSink + 'a>) -> Self {
+        self.sink_opt = Some(sink);
+        self
+    }
+
+    /// Clusters without a cluster output, see `with_membership_sink`.
+    pub fn new(prefix_length_opt: Option<usize>, capacity: usize) -> Self {
         let cluster_map = HashMap::with_capacity(capacity);
-        let cluster_order = Vec::with_capacity(capacity);
-        let cluster_csv_writer = cluster_csv_writer_opt
-            .map(|mut cluster_csv_writer| {
-                cluster_csv_writer
-                    .write_record(vec!["representative read id", "read id"])
-                    .map(|_| Some(cluster_csv_writer))
-            })
-            .unwrap_or(Ok(None))?;
-        Ok(Clusters {
+        let clusters = Vec::with_capacity(capacity);
+        Clusters {
             cluster_map,
-            cluster_order,
-            cluster_csv_writer,
+            clusters,
+            assignments_opt: None,
+            sink_opt: None,
+            sink_begun: false,
             total_records: 0,
-            prefix_length_opt,
+            key_extractor: Box::new(KeyWindow::prefix(prefix_length_opt)),
+            record_key_mates: false,
+            fold_case: false,
+            circular_opt: None,
+            wildcard_index_opt: None,
+            read_structures_opt: None,
+            cell_stats: HashMap::new(),
+            cell_order: Vec::new(),
+            invalid_records: InvalidRecords::default(),
+            policy_opt: None,
+            deferred_members: Vec::new(),
+        }
+    }
+
+    /// Membership is written to `cluster_output_opt` as CSV.
+    pub fn from_writer<W: io::Write + 'a>(
+        cluster_output_opt: Option<W>,
+        prefix_length_opt: Option<usize>,
+        capacity: usize,
+    ) -> io::Result<Self> {
+        let clusters = Clusters::new(prefix_length_opt, capacity);
+        Ok(match cluster_output_opt {
+            Some(cluster_output) => clusters
+                .with_membership_sink(Box::new(TableSink::new(cluster_output, TableFormat::Csv))),
+            None => clusters,
         })
     }
 }
 
-impl Clusters<File> {
+impl Clusters<'static> {
     pub fn from_file<P: AsRef<std::path::Path>>(
         cluster_output_path_opt: Option<P>,
         prefix_length_opt: Option<usize>,
         capacity: usize,
-    ) -> Result<Self, csv::Error> {
+    ) -> io::Result<Self> {
         cluster_output_path_opt
-            .map(|cluster_output_path| {
-                File::create(cluster_output_path).map(|cluster_output| Some(cluster_output))
-            })
+            .map(|cluster_output_path| File::create(cluster_output_path).map(Some))
             .unwrap_or(Ok(None))
-            .map_err(csv::Error::from)
             .and_then(|cluster_output| {
                 Clusters::from_writer(cluster_output, prefix_length_opt, capacity)
             })
@@ -150,11 +721,15 @@ impl Clusters<File> {
 mod test {
     use super::*;
 
-    use bio::io::fasta;
+    use crate::key::{pair_key, Umi};
+    use crate::membership::MemorySink;
+    use crate::representative::HighestQuality;
+    use bio::io::{fasta, fastq};
     use rand::Rng;
     use std::convert::TryFrom;
     use std::io::Cursor;
     use std::str;
+    use std::str::FromStr;
 
     fn random_seq(len: usize) -> Vec<u8> {
         const CHARSET: &[u8] = b"ACTG";
@@ -216,12 +791,352 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_pair_key() {
+        let clusters = Clusters::new(Some(10), 200).with_n_wildcard();
+        let pair = PairedRecord::try_from((
+            fasta::Record::with_attrs("id_a", None, &random_seq(20)),
+            fasta::Record::with_attrs("id_a", None, &random_seq(20)),
+        ))
+        .unwrap();
+        let key = clusters
+            .hash_members(vec![(0, pair.r1() as &dyn fastx::Record), (1, pair.r2())].into_iter());
+        let mut key_hasher = DefaultHasher::new();
+        Hash::hash_slice(
+            &pair_key(clusters.key_extractor.as_ref(), &pair),
+            &mut key_hasher,
+        );
+        assert_eq!(key.hash, key_hasher.finish());
+        assert_eq!(
+            key.wildcard_key_opt,
+            Some(pair_key(clusters.key_extractor.as_ref(), &pair))
+        );
+    }
+
+    #[test]
+    fn test_insert_group() {
+        let mut clusters = Clusters::new(Some(10), 200);
+        let seq_r1 = random_seq(20);
+        let seq_r2 = random_seq(20);
+        let group = |id: &str, index: &[u8]| {
+            RecordGroup::try_from(vec![
+                fasta::Record::with_attrs(id, None, &seq_r1),
+                fasta::Record::with_attrs(id, None, &seq_r2),
+                fasta::Record::with_attrs(id, None, index),
+            ])
+            .unwrap()
+        };
+        clusters
+            .insert_group(&group("id_a", b"AAAA"), &[0, 1])
+            .expect("don't break");
+        clusters
+            .insert_group(&group("id_b", b"CCCC"), &[0, 1])
+            .expect("don't break");
+        assert_eq!(clusters.unique_records(), 1);
+        clusters
+            .insert_group(&group("id_c", b"AAAA"), &[0, 1, 2])
+            .expect("don't break");
+        clusters
+            .insert_group(&group("id_d", b"CCCC"), &[0, 1, 2])
+            .expect("don't break");
+        assert_eq!(clusters.unique_records(), 3);
+
+        let pair = PairedRecord::try_from((
+            fasta::Record::with_attrs("id_e", None, &seq_r1),
+            fasta::Record::with_attrs("id_e", None, &seq_r2),
+        ))
+        .unwrap();
+        assert_eq!(
+            clusters.insert_pair(&pair).expect("don't break"),
+            Outcome::Duplicate {
+                representative_id: "id_a".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_insert_pair_read_structures() {
+        let mut clusters = Clusters::new(Some(5), 200).with_read_structures(vec![
+            ReadStructure::from_str("4B2U").unwrap(),
+            ReadStructure::from_str("T").unwrap(),
+        ]);
+        let cdna = random_seq(20);
+        let mut insert = |id: &str, r1: &[u8], r2: &[u8]| {
+            let record = PairedRecord::try_from((
+                fasta::Record::with_attrs(id, None, r1),
+                fasta::Record::with_attrs(id, None, r2),
+            ))
+            .unwrap();
+            clusters
+                .insert_pair(&record)
+                .expect("don't break")
+                .is_representative()
+        };
+        assert!(insert("id_a", b"AAAACC", &cdna));
+        // same cell, UMI and cDNA prefix
+        let mut cdna_tail = cdna[..5].to_vec();
+        cdna_tail.extend(random_seq(10));
+        assert!(!insert("id_b", b"AAAACC", &cdna_tail));
+        // different UMI
+        assert!(insert("id_c", b"AAAAGG", &cdna));
+        // different cell
+        assert!(insert("id_d", b"TTTTCC", &cdna));
+
+        let mut cell_stats_output = Cursor::new(Vec::new());
+        clusters
+            .write_cell_stats(&mut TableWriter::new(
+                &mut cell_stats_output,
+                TableFormat::Csv,
+            ))
+            .expect("don't break");
+        assert_eq!(clusters.cells(), 2);
+        assert_eq!(
+            str::from_utf8(cell_stats_output.into_inner().as_slice()).unwrap(),
+            "cell barcode,total reads,unique reads,duplicate reads\nAAAA,3,2,1\nTTTT,1,1,0\n"
+        );
+    }
+
+    #[test]
+    fn test_key_windows() {
+        let mut cluster_output = Cursor::new(Vec::new());
+        {
+            let mut clusters = Clusters::from_writer(Some(&mut cluster_output), None, 200)
+                .expect("don't break")
+                .with_key_windows(vec![
+                    KeyWindow::from_str("start:0:4").unwrap(),
+                    KeyWindow::from_str("start:0:0").unwrap(),
+                ]);
+            let mut insert = |id: &str, r1: &[u8], r2: &[u8]| {
+                let record = PairedRecord::try_from((
+                    fasta::Record::with_attrs(id, None, r1),
+                    fasta::Record::with_attrs(id, None, r2),
+                ))
+                .unwrap();
+                clusters
+                    .insert_pair(&record)
+                    .expect("don't break")
+                    .is_representative()
+            };
+            assert!(insert("id_a", b"ACGTAA", b"TTTT"));
+            // r2 is ignored
+            assert!(!insert("id_b", b"ACGTCC", b"GGGG"));
+            assert!(insert("id_c", b"CCGTAA", b"TTTT"));
+        }
+        assert_eq!(
+            str::from_utf8(cluster_output.into_inner().as_slice()).unwrap(),
+            "representative read id,read id,key mates\nid_a,id_a,1\nid_a,id_b,1\nid_c,id_c,1\n"
+        );
+    }
+
+    #[test]
+    fn test_key_extractor() {
+        let mut clusters =
+            Clusters::new(None, 200).with_key_extractor(Box::new(Umi { delimiter: '_' }));
+        let seq = random_seq(20);
+        for id in ["id_a_ACGT", "id_b_ACGT", "id_c_TTTT"].iter() {
+            let record = fasta::Record::with_attrs(id, None, &seq);
+            clusters.insert_single(&record).expect("don't break");
+        }
+        assert_eq!(clusters.unique_records(), 2);
+    }
+
+    #[test]
+    fn test_representative_policy() {
+        let mut cluster_output = Cursor::new(Vec::new());
+        {
+            let mut clusters = Clusters::from_writer(Some(&mut cluster_output), None, 200)
+                .expect("don't break")
+                .with_representative_policy(Box::new(HighestQuality))
+                .retain_assignments();
+            for (id, qual) in [("id_a", b"5555"), ("id_b", b"IIII"), ("id_c", b"IIII")].iter() {
+                let record = fastq::Record::with_attrs(id, None, b"ACGT", &qual[..]);
+                clusters.insert_single(&record).expect("don't break");
+            }
+            let cluster = clusters.cluster_of_record(0).expect("should be retained");
+            assert_eq!(cluster.id(), "id_b");
+            assert_eq!(cluster.representative_index(), 1);
+            clusters.finish().expect("don't break");
+        }
+        assert_eq!(
+            str::from_utf8(cluster_output.into_inner().as_slice()).unwrap(),
+            "representative read id,read id\nid_b,id_a\nid_b,id_b\nid_b,id_c\n"
+        );
+    }
+
+    #[test]
+    fn test_membership_sink() {
+        let mut memory_sink = MemorySink::default();
+        {
+            let mut clusters = Clusters::new(None, 200)
+                .with_membership_sink(Box::new(&mut memory_sink))
+                .with_representative_policy(Box::new(HighestQuality));
+            let mut insert = |id: &str, qual: &[u8]| {
+                let record = fastq::Record::with_attrs(id, None, b"ACGT", qual);
+                clusters.insert_single(&record).expect("don't break")
+            };
+            assert_eq!(
+                insert("id_a", b"5555"),
+                Outcome::Representative { replaced_opt: None }
+            );
+            assert_eq!(
+                insert("id_b", b"IIII"),
+                Outcome::Representative {
+                    replaced_opt: Some("id_a".to_owned())
+                }
+            );
+            assert_eq!(
+                insert("id_c", b"5555"),
+                Outcome::Duplicate {
+                    representative_id: "id_b".to_owned()
+                }
+            );
+            clusters.finish().expect("don't break");
+        }
+        let members: Vec<(&str, &str)> = memory_sink
+            .members()
+            .iter()
+            .map(|member| (member.representative_id.as_str(), member.id.as_str()))
+            .collect();
+        assert_eq!(
+            members,
+            vec![("id_b", "id_a"), ("id_b", "id_b"), ("id_b", "id_c")]
+        );
+    }
+
+    #[test]
+    fn test_case_folding() {
+        let mut clusters = Clusters::new(None, 200).with_case_folding();
+        for (id, seq) in [("id_a", b"MKVL"), ("id_b", b"mkvL")].iter() {
+            let record = fasta::Record::with_attrs(id, None, &seq[..]);
+            clusters.insert_single(&record).expect("don't break");
+        }
+        assert_eq!(clusters.unique_records(), 1);
+    }
+
+    #[test]
+    fn test_n_wildcard() {
+        let mut clusters = Clusters::new(None, 200)
+            .with_n_wildcard()
+            .retain_assignments();
+        for (id, seq) in [
+            ("id_a", &b"ACGTACGT"[..]),
+            ("id_b", b"ACGTNCGT"),
+            ("id_c", b"TTTTNNNN"),
+            ("id_d", b"TTTTNNNN"),
+            ("id_e", b"TTTTACGT"),
+        ]
+        .iter()
+        {
+            let record = fasta::Record::with_attrs(id, None, seq);
+            clusters.insert_single(&record).expect("don't break");
+        }
+        assert_eq!(clusters.unique_records(), 3);
+        let cluster_ids: Vec<&str> = (0..5)
+            .map(|i| {
+                clusters
+                    .cluster_of_record(i)
+                    .expect("should be retained")
+                    .id()
+            })
+            .collect();
+        assert_eq!(cluster_ids, vec!["id_a", "id_a", "id_c", "id_c", "id_e"]);
+    }
+
+    #[test]
+    fn test_insert_assigned() {
+        let mut cluster_output = Cursor::new(Vec::new());
+        {
+            let mut clusters = Clusters::from_writer(Some(&mut cluster_output), None, 200)
+                .expect("don't break")
+                .retain_assignments();
+            assert_eq!(
+                clusters
+                    .insert_assigned("id_a", 0, "id_b", 1)
+                    .expect("don't break"),
+                Outcome::Duplicate {
+                    representative_id: "id_b".to_owned()
+                }
+            );
+            assert!(clusters
+                .insert_assigned("id_b", 1, "id_b", 1)
+                .expect("don't break")
+                .is_representative());
+            assert_eq!(clusters.unique_records(), 1);
+            // a record sharing the representative's read ID is still a duplicate, and another
+            // representative with the same read ID has its own cluster
+            assert!(!clusters
+                .insert_assigned("id_b", 2, "id_b", 1)
+                .expect("don't break")
+                .is_representative());
+            assert!(clusters
+                .insert_assigned("id_b", 3, "id_b", 3)
+                .expect("don't break")
+                .is_representative());
+            assert_eq!(clusters.unique_records(), 2);
+            let cluster = clusters.cluster_of_record(0).expect("should be retained");
+            assert_eq!(cluster.id(), "id_b");
+            assert_eq!(cluster.representative_index(), 1);
+        }
+        assert_eq!(
+            str::from_utf8(cluster_output.into_inner().as_slice()).unwrap(),
+            "representative read id,read id\nid_b,id_a\nid_b,id_b\nid_b,id_b\nid_b,id_b\n"
+        );
+    }
+
+    #[test]
+    fn test_cluster_of_record() {
+        let mut clusters = Clusters::new(None, 200).retain_assignments();
+        let seq = random_seq(20);
+        for id in ["id_a", "id_b"].iter() {
+            let record = fasta::Record::with_attrs(id, None, &seq);
+            clusters.insert_single(&record).expect("don't break");
+        }
+        let cluster = clusters.cluster_of_record(1).expect("should be retained");
+        assert_eq!(cluster.id(), "id_a");
+        assert_eq!(cluster.size(), 2);
+        assert_eq!(cluster.representative_index(), 0);
+        assert!(clusters.cluster_of_record(2).is_none());
+    }
+
+    #[test]
+    fn test_retain_members() {
+        let mut clusters = Clusters::new(None, 200)
+            .with_representative_policy(Box::new(HighestQuality))
+            .retain_members();
+        for (id, seq, qual) in [
+            ("id_a", b"ACGT", b"5555"),
+            ("id_b", b"ACGT", b"IIII"),
+            ("id_c", b"TTTT", b"IIII"),
+        ]
+        .iter()
+        {
+            let record = fastq::Record::with_attrs(id, None, &seq[..], &qual[..]);
+            clusters.insert_single(&record).expect("don't break");
+        }
+        assert_eq!(clusters.cluster_of("id_a").map(Cluster::id), Some("id_b"));
+        assert!(clusters.cluster_of("id_d").is_none());
+        assert_eq!(
+            clusters.members("id_b").map(Iterator::collect::<Vec<&str>>),
+            Some(vec!["id_a", "id_b"])
+        );
+        // id_a no longer represents its cluster
+        assert!(clusters.members("id_a").is_none());
+        // members are retained along with assignments
+        assert_eq!(clusters.cluster_of_record(2).map(Cluster::id), Some("id_c"));
+        let sizes: Vec<(&str, u64)> = clusters
+            .clusters()
+            .map(|cluster| (cluster.id(), cluster.size()))
+            .collect();
+        assert_eq!(sizes, vec![("id_b", 2), ("id_c", 1)]);
+    }
+
     #[test]
     fn test_write_cluster_sizes() {
         let mut cluster_output = Cursor::new(Vec::new());
         let mut cluster_sizes_writer = Cursor::new(Vec::new());
         {
-            let mut cluster_sizes_output = csv::Writer::from_writer(&mut cluster_sizes_writer);
+            let mut cluster_sizes_output =
+                TableWriter::new(&mut cluster_sizes_writer, TableFormat::Csv);
             let mut clusters =
                 Clusters::from_writer(Some(&mut cluster_output), Some(10), 200).expect("asdasd");
             let seq1 = random_seq(20);
@@ -0,0 +1,156 @@
+use bio::data_structures::suffix_array::suffix_array;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// separates sequences in the suffix array text, must sort before every residue
+const SENTINEL: u8 = b'$';
+
+/// Minimums of ranges of values, each costing the log of the number of values.
+struct MinTree {
+    size: usize,
+    // the values are the leaves, from `size` on, and each other node is the minimum of its two
+    // children
+    nodes: Vec<usize>,
+}
+
+impl MinTree {
+    fn new(values: &[usize]) -> Self {
+        let size = values.len();
+        let mut nodes = vec![usize::MAX; 2 * size];
+        nodes[size..].copy_from_slice(values);
+        for i in (1..size).rev() {
+            nodes[i] = std::cmp::min(nodes[2 * i], nodes[2 * i + 1]);
+        }
+        MinTree { size, nodes }
+    }
+
+    /// The minimum of `values[start..end]`, `usize::MAX` if the range is empty.
+    fn min(&self, start: usize, end: usize) -> usize {
+        let (mut start, mut end) = (start + self.size, end + self.size);
+        let mut min = usize::MAX;
+        while start < end {
+            if start % 2 == 1 {
+                min = std::cmp::min(min, self.nodes[start]);
+                start += 1;
+            }
+            if end % 2 == 1 {
+                end -= 1;
+                min = std::cmp::min(min, self.nodes[end]);
+            }
+            start /= 2;
+            end /= 2;
+        }
+        min
+    }
+}
+
+/// Finds, for every sequence, the representative of its cluster: the first of the longest
+/// sequences it is an exact substring of, or itself if it isn't contained in any other. Identical
+/// sequences are contained in the first of them, so only the first is indexed. Containing
+/// sequences are looked up in a generalized suffix array of the distinct sequences, and the best
+/// of them found with a tree of range minimums over the suffixes, so each lookup costs the
+/// length of the sequence times the log of the total length.
+pub fn representatives(seqs: &[&[u8]]) -> Result<Vec<usize>, String> {
+    // distinct sequences by their first index, and the distinct sequence of every index
+    let mut distinct = Vec::new();
+    let mut distinct_of = Vec::with_capacity(seqs.len());
+    let mut distinct_map: HashMap<&[u8], usize> = HashMap::with_capacity(seqs.len());
+    for (i, seq) in seqs.iter().enumerate() {
+        if let Some(byte) = seq.iter().find(|byte| **byte <= SENTINEL) {
+            return Err(format!(
+                "sequence {} has the character 0x{:02x}, containment needs characters after '{}'",
+                i + 1,
+                byte,
+                SENTINEL as char
+            ));
+        }
+        let d = *distinct_map.entry(seq).or_insert_with(|| {
+            distinct.push(i);
+            distinct.len() - 1
+        });
+        distinct_of.push(d);
+    }
+
+    let mut text = Vec::with_capacity(distinct.iter().map(|i| seqs[*i].len() + 1).sum());
+    let mut starts = Vec::with_capacity(distinct.len());
+    for i in distinct.iter() {
+        starts.push(text.len());
+        text.extend_from_slice(seqs[*i]);
+        text.push(SENTINEL);
+    }
+    let positions = suffix_array(&text);
+    // guaranteed to be in a sequence since positions are at most the last sentinel
+    let owner = |position: usize| starts.partition_point(|start| *start <= position) - 1;
+
+    // longer sequences first, then input order
+    let mut order: Vec<usize> = (0..distinct.len()).collect();
+    order.sort_by_key(|d| (std::cmp::Reverse(seqs[distinct[*d]].len()), distinct[*d]));
+    let mut ranks = vec![0; distinct.len()];
+    for (rank, d) in order.iter().enumerate() {
+        ranks[*d] = rank;
+    }
+    let owner_ranks = MinTree::new(
+        &positions
+            .iter()
+            .map(|position| ranks[owner(*position)])
+            .collect::<Vec<usize>>(),
+    );
+
+    let mut distinct_representatives = vec![0; distinct.len()];
+    for (rank, d) in order.iter().enumerate() {
+        let seq = seqs[distinct[*d]];
+        // compares the suffix truncated to the length of the sequence
+        let compare = |position: &usize| {
+            let end = std::cmp::min(position + seq.len(), text.len());
+            text[*position..end].cmp(seq)
+        };
+        let first = positions.partition_point(|position| compare(position) == Ordering::Less);
+        let last = positions.partition_point(|position| compare(position) != Ordering::Greater);
+        // the sequence occurs in itself, so only a better ranked owner contains it
+        let best_rank = owner_ranks.min(first, last);
+        // containers come first in the order so their representative is already known
+        distinct_representatives[*d] = if best_rank < rank {
+            distinct_representatives[order[best_rank]]
+        } else {
+            distinct[*d]
+        };
+    }
+    Ok(distinct_of
+        .iter()
+        .map(|d| distinct_representatives[*d])
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_representatives() {
+        let seqs: Vec<&[u8]> = vec![
+            b"CGTA",
+            b"AACGTACC",
+            b"TTTT",
+            b"AACGTACC",
+            b"GGAACGTACCGG",
+            b"TT",
+        ];
+        assert_eq!(
+            representatives(&seqs).expect("don't break"),
+            vec![4, 4, 2, 4, 4, 2]
+        );
+        assert!(representatives(&[b"AC$GT"]).is_err());
+    }
+
+    #[test]
+    fn test_min_tree() {
+        let values = vec![5, 3, 8, 1, 9, 2, 7];
+        let tree = MinTree::new(&values);
+        for start in 0..values.len() {
+            for end in start..=values.len() {
+                let expected = values[start..end].iter().min().copied();
+                assert_eq!(tree.min(start, end), expected.unwrap_or(usize::MAX));
+            }
+        }
+    }
+}
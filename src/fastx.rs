@@ -1,12 +1,46 @@
 use bio::io::{fasta, fastq};
+use std::any::Any;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::Write;
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+use super::format;
 
 pub trait Record {
     fn id(&self) -> &str;
+    fn desc(&self) -> Option<&str>;
     fn seq(&self) -> &[u8];
+    /// `None` for formats without quality scores.
+    fn qual(&self) -> Option<&[u8]>;
     fn check(&self) -> Result<(), &str>;
+    /// For writers that keep more of a record when it is in their own format.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl Record for Box<dyn Record> {
+    fn id(&self) -> &str {
+        (**self).id()
+    }
+
+    fn desc(&self) -> Option<&str> {
+        (**self).desc()
+    }
+
+    fn seq(&self) -> &[u8] {
+        (**self).seq()
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        (**self).qual()
+    }
+
+    fn check(&self) -> Result<(), &str> {
+        (**self).check()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        (**self).as_any()
+    }
 }
 
 impl Record for fasta::Record {
@@ -14,13 +48,25 @@ impl Record for fasta::Record {
         self.id()
     }
 
+    fn desc(&self) -> Option<&str> {
+        self.desc()
+    }
+
     fn seq(&self) -> &[u8] {
         self.seq()
     }
 
+    fn qual(&self) -> Option<&[u8]> {
+        None
+    }
+
     fn check(&self) -> Result<(), &str> {
         self.check()
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 impl Record for fastq::Record {
@@ -28,62 +74,436 @@ impl Record for fastq::Record {
         self.id()
     }
 
+    fn desc(&self) -> Option<&str> {
+        self.desc()
+    }
+
     fn seq(&self) -> &[u8] {
         self.seq()
     }
 
+    fn qual(&self) -> Option<&[u8]> {
+        Some(self.qual())
+    }
+
     fn check(&self) -> Result<(), &str> {
         self.check()
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// What `--mark-duplicates` records about a read.
+pub struct DuplicateMark<'a> {
+    pub duplicate: bool,
+    pub representative_id: &'a str,
+    pub cluster_size: u64,
 }
 
 pub trait Writer<T: Record> {
     fn write_record(&mut self, record: &T) -> Result<(), std::io::Error>;
+    /// Writes the record with its description replaced by `desc`.
+    fn write_record_with_desc(
+        &mut self,
+        record: &T,
+        desc: Option<&str>,
+    ) -> Result<(), std::io::Error>;
+
+    /// Appends the duplicate status (DUP), representative read id (RID) and cluster size (CS) to
+    /// the record's description.
+    fn write_marked_record(
+        &mut self,
+        record: &T,
+        mark: &DuplicateMark,
+    ) -> Result<(), std::io::Error> {
+        let tags = format!(
+            "DUP:{} RID:{} CS:{}",
+            if mark.duplicate { "Y" } else { "N" },
+            mark.representative_id,
+            mark.cluster_size
+        );
+        let desc = record
+            .desc()
+            .map(|desc| format!("{} {}", desc, tags))
+            .unwrap_or(tags);
+        self.write_record_with_desc(record, Some(&desc))
+    }
+
+    /// Writes member `mate` of a pair or group of `mates` records. Formats that don't record
+    /// mates write it like any other record.
+    fn write_mate(
+        &mut self,
+        record: &T,
+        _mate: usize,
+        _mates: usize,
+    ) -> Result<(), std::io::Error> {
+        self.write_record(record)
+    }
+
+    /// Writes member `mate` of a pair or group of `mates` records with its duplicate mark.
+    fn write_marked_mate(
+        &mut self,
+        record: &T,
+        _mate: usize,
+        _mates: usize,
+        mark: &DuplicateMark,
+    ) -> Result<(), std::io::Error> {
+        self.write_marked_record(record, mark)
+    }
+
+    /// Writes out everything buffered, reporting the errors dropping the writer would ignore.
+    /// Nothing can be written after.
+    fn finish(&mut self) -> Result<(), std::io::Error>;
 }
 
-impl<T: Write> Writer<fasta::Record> for fasta::Writer<T> {
-    fn write_record(&mut self, record: &fasta::Record) -> Result<(), std::io::Error> {
-        self.write_record(&record)
+impl<T: Record, W: Writer<T> + ?Sized> Writer<T> for Box<W> {
+    fn write_record(&mut self, record: &T) -> Result<(), std::io::Error> {
+        (**self).write_record(record)
+    }
+
+    fn write_record_with_desc(
+        &mut self,
+        record: &T,
+        desc: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        (**self).write_record_with_desc(record, desc)
     }
+
+    fn write_marked_record(
+        &mut self,
+        record: &T,
+        mark: &DuplicateMark,
+    ) -> Result<(), std::io::Error> {
+        (**self).write_marked_record(record, mark)
+    }
+
+    fn write_mate(&mut self, record: &T, mate: usize, mates: usize) -> Result<(), std::io::Error> {
+        (**self).write_mate(record, mate, mates)
+    }
+
+    fn write_marked_mate(
+        &mut self,
+        record: &T,
+        mate: usize,
+        mates: usize,
+        mark: &DuplicateMark,
+    ) -> Result<(), std::io::Error> {
+        (**self).write_marked_mate(record, mate, mates, mark)
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        (**self).finish()
+    }
+}
+
+/// Writes records of any format as FASTA, wrapping sequences over lines of at most
+/// `line_width_opt` bases. Qualities are dropped when writing records from formats that have
+/// them.
+pub struct FastaWriter<W: Write> {
+    writer: BufWriter<W>,
+    line_width_opt: Option<usize>,
 }
 
-impl<T: Write> Writer<fastq::Record> for fastq::Writer<T> {
-    fn write_record(&mut self, record: &fastq::Record) -> Result<(), std::io::Error> {
-        self.write_record(&record)
+impl<W: Write> FastaWriter<W> {
+    pub fn new(writer: W, line_width_opt: Option<usize>) -> Self {
+        FastaWriter {
+            writer: BufWriter::new(writer),
+            line_width_opt,
+        }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum FastxType {
-    Fastq,
-    Fasta,
-    Invalid,
+impl FastaWriter<File> {
+    pub fn to_file<P: AsRef<std::path::Path>>(
+        path: P,
+        line_width_opt: Option<usize>,
+    ) -> Result<Self, std::io::Error> {
+        File::create(path).map(|file| FastaWriter::new(file, line_width_opt))
+    }
 }
 
-pub fn fastx_type<P: AsRef<std::path::Path>>(path: P) -> Result<FastxType, std::io::Error> {
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(err) => return Err(err),
-    };
-    let mut byte = [0; 1];
-    if let Err(err) = file.read(&mut byte) {
-        return Err(err);
+impl<W: Write, R: Record> Writer<R> for FastaWriter<W> {
+    fn write_record(&mut self, record: &R) -> Result<(), std::io::Error> {
+        self.write_record_with_desc(record, record.desc())
     }
 
-    match byte[0] as char {
-        '>' => Ok(FastxType::Fasta),
-        '@' => Ok(FastxType::Fastq),
-        _ => Ok(FastxType::Invalid),
+    fn write_record_with_desc(
+        &mut self,
+        record: &R,
+        desc: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        self.writer.write_all(b">")?;
+        self.writer.write_all(record.id().as_bytes())?;
+        if let Some(desc) = desc {
+            write!(self.writer, " {}", desc)?;
+        }
+        self.writer.write_all(b"\n")?;
+        let seq = record.seq();
+        let line_width = self.line_width_opt.unwrap_or(seq.len()).max(1);
+        for line in seq.chunks(line_width) {
+            self.writer.write_all(line)?;
+            self.writer.write_all(b"\n")?;
+        }
+        if seq.is_empty() {
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
+
+/// Writes records of any format as FASTQ, using a constant fill quality for records without
+/// qualities.
+pub struct FastqWriter<W: Write> {
+    writer: fastq::Writer<W>,
+    fill_quality: u8,
+}
+
+impl<W: Write> FastqWriter<W> {
+    pub fn new(writer: W, fill_quality: u8) -> Self {
+        FastqWriter {
+            writer: fastq::Writer::new(writer),
+            fill_quality,
+        }
+    }
+}
+
+impl FastqWriter<File> {
+    pub fn to_file<P: AsRef<std::path::Path>>(
+        path: P,
+        fill_quality: u8,
+    ) -> Result<Self, std::io::Error> {
+        File::create(path).map(|file| FastqWriter::new(file, fill_quality))
+    }
+}
+
+impl<W: Write, R: Record> Writer<R> for FastqWriter<W> {
+    fn write_record(&mut self, record: &R) -> Result<(), std::io::Error> {
+        self.write_record_with_desc(record, record.desc())
+    }
+
+    fn write_record_with_desc(
+        &mut self,
+        record: &R,
+        desc: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        match record.qual() {
+            Some(qual) => self.writer.write(record.id(), desc, record.seq(), qual),
+            None => {
+                let qual = vec![self.fill_quality; record.seq().len()];
+                self.writer.write(record.id(), desc, record.seq(), &qual)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
+
+/// Reads FASTQ whose sequence and quality may be wrapped over several lines, as in old Sanger
+/// files. Quality lines may start with `@` or `+`, so a wrapped quality ends once it is as long as
+/// the sequence, or at a line starting with `@` that would make it longer. A sequence on one line
+/// has its quality on one line, however long.
+pub struct FastqReader<R: BufRead> {
+    reader: R,
+    line: String,
+    // a header read while looking for the end of a wrapped quality
+    pending_header_opt: Option<String>,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    pub fn new(reader: R) -> Self {
+        FastqReader {
+            reader,
+            line: String::new(),
+            pending_header_opt: None,
+        }
+    }
+
+    /// Reads the next line without trailing whitespace, `None` at the end of the input.
+    fn read_line(&mut self) -> Result<Option<&str>, Error> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.line.trim_end()))
+    }
+}
+
+impl<R: BufRead> Iterator for FastqReader<R> {
+    type Item = Result<fastq::Record, Error>;
+
+    fn next(&mut self) -> Option<Result<fastq::Record, Error>> {
+        let header = loop {
+            if let Some(header) = self.pending_header_opt.take() {
+                break header;
+            }
+            match self.read_line() {
+                Ok(None) => return None,
+                Ok(Some("")) => continue,
+                Ok(Some(line)) => break line.to_owned(),
+                Err(err) => return Some(Err(err)),
+            }
+        };
+        let header = match header.strip_prefix('@') {
+            Some(header) => header,
+            None => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "expected @ at the start of a record",
+                )))
+            }
+        };
+        let mut fields = header.splitn(2, ' ');
+        // splitn always returns at least one field
+        let id = fields.next().unwrap();
+        let desc_opt = fields.next();
+
+        let mut seq = Vec::new();
+        let mut seq_lines = 0;
+        loop {
+            match self.read_line() {
+                Ok(Some(line)) if line.starts_with('+') => break,
+                Ok(Some(line)) => {
+                    seq.extend_from_slice(line.as_bytes());
+                    seq_lines += 1;
+                }
+                Ok(None) => {
+                    let message = format!("reached the end of the input before the + of {}", id);
+                    return Some(Err(Error::new(ErrorKind::UnexpectedEof, message)));
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        // a truncated quality is left for the record check to report
+        let mut qual = Vec::new();
+        if seq_lines == 1 {
+            match self.read_line() {
+                Ok(Some(line)) => qual.extend_from_slice(line.as_bytes()),
+                Ok(None) => (),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        while seq_lines > 1 && qual.len() < seq.len() {
+            match self.read_line() {
+                Ok(Some(line)) if line.starts_with('@') && qual.len() + line.len() > seq.len() => {
+                    self.pending_header_opt = Some(line.to_owned());
+                    break;
+                }
+                Ok(Some(line)) => qual.extend_from_slice(line.as_bytes()),
+                Ok(None) => break,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        Some(Ok(fastq::Record::with_attrs(id, desc_opt, &seq, &qual)))
     }
 }
 
-impl std::fmt::Display for FastxType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
-            FastxType::Fasta => "fasta",
-            FastxType::Fastq => "fastq",
-            FastxType::Invalid => "invalid",
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Fasta,
+    Fastq,
+}
+
+impl format::Format for Format {
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Fasta => "fasta",
+            Format::Fastq => "fastq",
+        }
+    }
+
+    fn detect(&self, head: &[u8]) -> bool {
+        match self {
+            Format::Fasta => head.starts_with(b">"),
+            Format::Fastq => head.starts_with(b"@"),
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Format::Fasta => &["fasta", "fa", "fna", "faa"],
+            Format::Fastq => &["fastq", "fq"],
+        }
+    }
+
+    fn reader(&self, path: &Path) -> Result<format::Records, std::io::Error> {
+        let (file, tracker) = format::open(path)?;
+        let records: format::Records = match self {
+            Format::Fasta => Box::new(
+                fasta::Reader::new(file)
+                    .records()
+                    .map(|result| result.map(|record| Box::new(record) as Box<dyn Record>)),
+            ),
+            Format::Fastq => Box::new(
+                FastqReader::new(file)
+                    .map(|result| result.map(|record| Box::new(record) as Box<dyn Record>)),
+            ),
         };
-        write!(f, "{}", s)
+        Ok(Box::new(format::LocatedRecords::new(
+            records,
+            path,
+            Some(tracker),
+        )))
+    }
+
+    fn writer(
+        &self,
+        path: &Path,
+        _input: &Path,
+        options: &format::WriterOptions,
+    ) -> Result<format::RecordWriter, std::io::Error> {
+        let writer: format::RecordWriter = match self {
+            Format::Fasta => Box::new(FastaWriter::to_file(path, options.line_width_opt)?),
+            Format::Fastq => Box::new(FastqWriter::to_file(path, options.fill_quality)?),
+        };
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fastq_reader_multi_line() {
+        let fastq = "@id_a first\nACGT\nAC\n+\n@III\n+I\n\n@id_b\nACGT\n+id_b\nIIII\n";
+        let records: Vec<fastq::Record> = FastqReader::new(fastq.as_bytes())
+            .map(|result| result.expect("don't break"))
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "id_a");
+        assert_eq!(records[0].desc(), Some("first"));
+        assert_eq!(records[0].seq(), b"ACGTAC");
+        assert_eq!(records[0].qual(), b"@III+I");
+        assert_eq!(records[1].id(), "id_b");
+        assert_eq!(records[1].qual(), b"IIII");
+
+        // short qualities end at the next header
+        let fastq = "@id_a\nACGT\n+\nII\n@id_b\nACG\nTA\n+\nII\n@id_c\nA\n+\nI\n";
+        let records: Vec<fastq::Record> = FastqReader::new(fastq.as_bytes())
+            .map(|result| result.expect("don't break"))
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].qual(), b"II");
+        assert_eq!(records[1].qual(), b"II");
+        assert_eq!(records[2].id(), "id_c");
+        assert_eq!(records[2].qual(), b"I");
+    }
+
+    #[test]
+    fn test_fasta_writer_line_width() {
+        let record = fasta::Record::with_attrs("id_a", Some("first"), b"ACGTACG");
+        let mut writer = FastaWriter::new(Vec::new(), Some(3));
+        writer.write_record(&record).expect("don't break");
+        let output = writer.writer.into_inner().expect("don't break");
+        assert_eq!(output, b">id_a first\nACG\nTAC\nG\n");
     }
 }
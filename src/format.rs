@@ -0,0 +1,375 @@
+use flate2::read::MultiGzDecoder;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::fastx;
+use super::sam;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+// enough to tell every format apart
+const HEAD_LENGTH: u64 = 16;
+
+pub type Records = Box<dyn Iterator<Item = Result<Box<dyn fastx::Record>, Error>>>;
+pub type RecordWriter = Box<dyn fastx::Writer<Box<dyn fastx::Record>>>;
+
+/// Settings only some writers use.
+pub struct WriterOptions {
+    /// Quality given to every base of records without qualities.
+    pub fill_quality: u8,
+    /// Bases per line of wrapped FASTA sequences, unwrapped if `None`.
+    pub line_width_opt: Option<usize>,
+}
+
+/// A file format reads can be read from and written to. Records are passed around as
+/// `fastx::Record` trait objects so any reader can be paired with any writer, and adding a format
+/// only means implementing this trait and listing it in `formats`.
+pub trait Format {
+    fn name(&self) -> &'static str;
+
+    /// Whether a file starting with `head` is in this format. `head` is decompressed and starts
+    /// after any byte order mark and leading whitespace.
+    fn detect(&self, head: &[u8]) -> bool;
+
+    /// File extensions to fall back on when the contents are not recognised, without the
+    /// compression extension.
+    fn extensions(&self) -> &'static [&'static str];
+
+    fn reader(&self, path: &Path) -> Result<Records, Error>;
+
+    /// `input` is the file the records are read from, for formats that carry its header over.
+    fn writer(
+        &self,
+        path: &Path,
+        input: &Path,
+        options: &WriterOptions,
+    ) -> Result<RecordWriter, Error>;
+
+    /// Whether each record of the file is followed by its mate, as in paired SAM.
+    fn interleaved(&self, _path: &Path) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// Every supported format in detection order. SAM headers also look like FASTQ records so SAM
+/// comes first.
+pub fn formats() -> Vec<&'static dyn Format> {
+    vec![
+        &sam::Format::Bam,
+        &sam::Format::Sam,
+        &fastx::Format::Fasta,
+        &fastx::Format::Fastq,
+    ]
+}
+
+pub fn by_name(name: &str) -> Option<&'static dyn Format> {
+    formats().into_iter().find(|format| format.name() == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    formats().into_iter().map(|format| format.name()).collect()
+}
+
+/// Detects the format from the start of the file's contents, falling back to its extension.
+pub fn detect<P: AsRef<Path>>(path: P) -> Result<&'static dyn Format, Error> {
+    let path = path.as_ref();
+    let (reader, tracker) = open(path)?;
+    let skipped = tracker.position().byte;
+    let mut head = Vec::new();
+    reader.take(HEAD_LENGTH).read_to_end(&mut head)?;
+    if let Some(format) = formats().into_iter().find(|format| format.detect(&head)) {
+        return Ok(format);
+    }
+
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let file_name = file_name.trim_end_matches(".gz").trim_end_matches(".zst");
+    let extension_opt = file_name.rsplit_once('.').map(|(_, extension)| extension);
+    if let Some(extension) = extension_opt {
+        let format_opt = formats()
+            .into_iter()
+            .find(|format| format.extensions().contains(&extension));
+        if let Some(format) = format_opt {
+            return Ok(format);
+        }
+    }
+
+    let found = match head.first() {
+        None => "the end of the file".to_owned(),
+        Some(byte) if byte.is_ascii_graphic() => format!("'{}'", *byte as char),
+        Some(byte) => format!("0x{:02x}", byte),
+    };
+    let message = format!(
+        "{} is not in a supported format, found {} at byte {}, expected one of {}",
+        path.display(),
+        found,
+        skipped,
+        names().join(", ")
+    );
+    Err(Error::new(ErrorKind::InvalidData, message))
+}
+
+/// Opens a file, decompressing it if needed and skipping a UTF-8 byte order mark and leading
+/// whitespace. The tracker follows how far into the decompressed file the reader is.
+pub fn open(path: &Path) -> Result<(Box<dyn BufRead>, Tracker), Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let magic = file.fill_buf()?;
+    let inner: Box<dyn BufRead> = if magic.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(
+            file,
+        )?))
+    } else if magic.starts_with(BZIP2_MAGIC) || magic.starts_with(XZ_MAGIC) {
+        let compression = if magic.starts_with(BZIP2_MAGIC) {
+            "bzip2"
+        } else {
+            "xz"
+        };
+        let message = format!(
+            "{} is {} compressed, only gzip and zstd are supported",
+            path.display(),
+            compression
+        );
+        return Err(Error::new(ErrorKind::InvalidData, message));
+    } else {
+        Box::new(file)
+    };
+    let tracker = Tracker::default();
+    let mut reader = LineReader {
+        inner,
+        tracker: tracker.clone(),
+        at_line_start: true,
+        available: 0,
+        ends_line: false,
+    };
+
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    loop {
+        let buffer = reader.fill_buf()?;
+        let whitespace = buffer
+            .iter()
+            .take_while(|byte| byte.is_ascii_whitespace())
+            .count();
+        if whitespace == 0 {
+            break;
+        }
+        reader.consume(whitespace);
+    }
+    Ok((Box::new(reader), tracker))
+}
+
+/// Where a reader is in its decompressed input.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Position {
+    /// 1-based number of the line being read, 0 before the first line.
+    pub line: u64,
+    /// Offset of the start of that line.
+    pub line_byte: u64,
+    /// Number of bytes read.
+    pub byte: u64,
+}
+
+/// Shares the position of a reader with whoever reports its errors.
+#[derive(Clone, Default)]
+pub struct Tracker(Rc<Cell<Position>>);
+
+impl Tracker {
+    pub fn position(&self) -> Position {
+        self.0.get()
+    }
+}
+
+/// Hands out at most one line at a time so parsers buffering on top of it never read ahead of
+/// the line they are parsing, which keeps the tracked position exact.
+struct LineReader {
+    inner: Box<dyn BufRead>,
+    tracker: Tracker,
+    at_line_start: bool,
+    // length of the slice last returned by fill_buf and whether it ended with a newline
+    available: usize,
+    ends_line: bool,
+}
+
+impl Read for LineReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let available = self.fill_buf()?;
+        let length = std::cmp::min(available.len(), buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl BufRead for LineReader {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        let buffer = self.inner.fill_buf()?;
+        let length = buffer
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(buffer.len(), |newline| newline + 1);
+        self.available = length;
+        self.ends_line = length > 0 && buffer[length - 1] == b'\n';
+        Ok(&buffer[..length])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        let mut position = self.tracker.position();
+        if self.at_line_start {
+            position.line += 1;
+            position.line_byte = position.byte;
+        }
+        position.byte += amount as u64;
+        self.tracker.0.set(position);
+        self.at_line_start = amount == self.available && self.ends_line;
+        self.available -= std::cmp::min(amount, self.available);
+        self.inner.consume(amount);
+    }
+}
+
+/// Adds where an error happened to its message, keeping its kind.
+pub fn locate_error(err: Error, location: &str) -> Error {
+    let message = err.to_string();
+    // rust-bio messages are sentences
+    let message = message.trim_end_matches('.');
+    Error::new(err.kind(), format!("{} at {}", message, location))
+}
+
+/// Counts the records read from a file and says where in it errors happened.
+pub struct LocatedRecords<R> {
+    records: R,
+    path: String,
+    // formats without lines, such as BAM, are not tracked
+    tracker_opt: Option<Tracker>,
+    count: u64,
+}
+
+impl<R> LocatedRecords<R> {
+    pub fn new(records: R, path: &Path, tracker_opt: Option<Tracker>) -> Self {
+        LocatedRecords {
+            records,
+            path: path.display().to_string(),
+            tracker_opt,
+            count: 0,
+        }
+    }
+}
+
+impl<T, R: Iterator<Item = Result<T, Error>>> Iterator for LocatedRecords<R> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Result<T, Error>> {
+        self.count += 1;
+        self.records.next().map(|result| {
+            result.map_err(|err| {
+                let location = match self.tracker_opt.as_ref().map(Tracker::position) {
+                    Some(position) => format!(
+                        "record {}, line {}, byte {}",
+                        self.count, position.line, position.line_byte
+                    ),
+                    None => format!("record {}", self.count),
+                };
+                locate_error(err, &format!("{} of {}", location, self.path))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn detect_contents(name: &str, contents: &[u8]) -> Result<&'static str, String> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        detect(&path).map(|format| format.name()).map_err(|err| {
+            err.to_string()
+                .replace(&format!("{}", path.display()), name)
+        })
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect_contents("a", b">id\nACGT\n"), Ok("fasta"));
+        assert_eq!(detect_contents("a", b"@id\nACGT\n+\nIIII\n"), Ok("fastq"));
+        assert_eq!(detect_contents("a", b"@HD\tVN:1.6\n"), Ok("sam"));
+        assert_eq!(
+            detect_contents("a", b"\xef\xbb\xbf\r\n\n  >id\r\nACGT\r\n"),
+            Ok("fasta")
+        );
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"@id\nACGT\n+\nIIII\n").unwrap();
+        assert_eq!(
+            detect_contents("a", &encoder.finish().unwrap()),
+            Ok("fastq")
+        );
+        let compressed = zstd::stream::encode_all(&b">id\nACGT\n"[..], 0).unwrap();
+        assert_eq!(detect_contents("a", &compressed), Ok("fasta"));
+    }
+
+    #[test]
+    fn test_detect_extension() {
+        // SAM without a header starts with a read name
+        let sam = b"id\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n";
+        assert_eq!(detect_contents("reads.sam", sam), Ok("sam"));
+        assert_eq!(detect_contents("reads.FQ.gz", b""), Ok("fastq"));
+    }
+
+    #[test]
+    fn test_located_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("reads.fastq");
+        std::fs::write(
+            &path,
+            "\n@id_a\nACGT\n+\nIIII\n@id_b\nACGT\n+\nIIII\nid_c\nACGT\n",
+        )
+        .unwrap();
+        let err = fastx::Format::Fastq
+            .reader(&path)
+            .unwrap()
+            .find_map(|result| result.err())
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "expected @ at the start of a record at record 3, line 10, byte 37 of {}",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_detect_invalid() {
+        assert_eq!(
+            detect_contents("reads.txt", b"\n\nACGT\n"),
+            Err("reads.txt is not in a supported format, found 'A' at byte 2, expected one of bam, sam, fasta, fastq".to_owned())
+        );
+        assert_eq!(
+            detect_contents("reads", b""),
+            Err("reads is not in a supported format, found the end of the file at byte 0, expected one of bam, sam, fasta, fastq".to_owned())
+        );
+        assert_eq!(
+            detect_contents("reads", b"BZh91AY"),
+            Err("reads is bzip2 compressed, only gzip and zstd are supported".to_owned())
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+
+use super::fastx;
+use super::format;
+use super::paired::PairedRecord;
+
+/// Reads from any number of synchronized inputs (e.g. R1, R2, I1 and I2) that share a read ID.
+pub struct RecordGroup<T: fastx::Record> {
+    members: Vec<T>,
+}
+
+impl<T: fastx::Record> RecordGroup<T> {
+    pub fn id(&self) -> &str {
+        self.members[0].id()
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        self.members.iter().enumerate().try_for_each(|(i, member)| {
+            member
+                .check()
+                .map_err(|err| format!("input {}: {}", i + 1, err))
+        })
+    }
+
+    pub fn members(&self) -> &[T] {
+        &self.members
+    }
+}
+
+impl<T: fastx::Record> From<PairedRecord<T>> for RecordGroup<T> {
+    fn from(record: PairedRecord<T>) -> Self {
+        let (r1, r2) = record.into();
+        RecordGroup {
+            members: vec![r1, r2],
+        }
+    }
+}
+
+impl<T: fastx::Record> TryFrom<Vec<T>> for RecordGroup<T> {
+    type Error = Error;
+
+    fn try_from(members: Vec<T>) -> Result<Self, Self::Error> {
+        if members.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "read group must have at least one member",
+            ));
+        }
+        if members.iter().all(|member| member.id() == members[0].id()) {
+            Ok(RecordGroup { members })
+        } else {
+            let ids: Vec<&str> = members.iter().map(|member| member.id()).collect();
+            let message = format!("read group had different read IDs: ({})", ids.join(", "));
+            Err(Error::new(ErrorKind::InvalidData, message))
+        }
+    }
+}
+
+pub struct RecordGroups<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> {
+    records: Vec<R>,
+    names: Vec<String>,
+    count: u64,
+}
+
+impl<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> RecordGroups<T, R> {
+    pub fn new(records: Vec<R>) -> Self {
+        let names = (1..=records.len())
+            .map(|i| format!("input {}", i))
+            .collect();
+        RecordGroups {
+            records,
+            names,
+            count: 0,
+        }
+    }
+
+    /// Names the inputs in errors, usually by their paths.
+    pub fn with_names(mut self, names: &[&str]) -> Self {
+        self.names = names.iter().map(|name| (*name).to_owned()).collect();
+        self
+    }
+}
+
+impl<A: fastx::Record, T: Iterator<Item = Result<A, std::io::Error>>> Iterator
+    for RecordGroups<A, T>
+{
+    type Item = Result<RecordGroup<A>, Error>;
+
+    fn next(&mut self) -> Option<Result<RecordGroup<A>, Error>> {
+        self.count += 1;
+        let results: Vec<Option<Result<A, Error>>> = self
+            .records
+            .iter_mut()
+            .map(|records| records.next())
+            .collect();
+
+        let ended: Vec<usize> = (0..results.len())
+            .filter(|i| results[*i].is_none())
+            .collect();
+        if ended.len() == results.len() {
+            return None;
+        }
+        if let Some(i) = ended.first() {
+            // guaranteed to exist since not every input ended
+            let j = (0..results.len()).find(|j| results[*j].is_some()).unwrap();
+            let message = format!(
+                "reached the end of {} after {} records, before {}",
+                self.names[*i],
+                self.count - 1,
+                self.names[j]
+            );
+            return Some(Err(Error::new(ErrorKind::UnexpectedEof, message)));
+        }
+
+        let members: Result<Vec<A>, Error> = results.into_iter().flatten().collect();
+        let location = format!("record {} of {}", self.count, self.names.join(", "));
+        Some(members.and_then(|members| {
+            RecordGroup::try_from(members).map_err(|err| format::locate_error(err, &location))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bio::io::fasta;
+
+    #[test]
+    fn test_groups() {
+        let records = vec!["r1", "r2", "i1"]
+            .into_iter()
+            .map(|_| {
+                vec![
+                    Ok(fasta::Record::with_attrs("id_a", None, &[])),
+                    Ok(fasta::Record::with_attrs("id_b", None, &[])),
+                ]
+                .into_iter()
+            })
+            .collect();
+        let ids: Vec<String> = RecordGroups::new(records)
+            .map(|result| {
+                let record = result.expect("should not error");
+                assert_eq!(record.members().len(), 3);
+                record.id().to_owned()
+            })
+            .collect();
+        assert_eq!(ids, vec!["id_a", "id_b"]);
+    }
+
+    #[test]
+    fn test_input_shorter() {
+        let records = vec![
+            vec![Ok(fasta::Record::with_attrs("id_a", None, &[]))].into_iter(),
+            vec![Ok(fasta::Record::with_attrs("id_a", None, &[]))].into_iter(),
+            vec![].into_iter(),
+        ];
+        let error = RecordGroups::new(records)
+            .next()
+            .expect("should return an element")
+            .err()
+            .expect("should return an error");
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            error.to_string(),
+            "reached the end of input 3 after 0 records, before input 1"
+        );
+    }
+
+    #[test]
+    fn test_different_ids() {
+        let records = vec![
+            vec![Ok(fasta::Record::with_attrs("id_a", None, &[]))].into_iter(),
+            vec![Ok(fasta::Record::with_attrs("id_a", None, &[]))].into_iter(),
+            vec![Ok(fasta::Record::with_attrs("id_b", None, &[]))].into_iter(),
+        ];
+        let error = RecordGroups::new(records)
+            .next()
+            .expect("should return an element")
+            .err()
+            .expect("should return an error");
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "read group had different read IDs: (id_a, id_a, id_b) at record 1 of input 1, input 2, input 3"
+        );
+    }
+}
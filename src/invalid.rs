@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidPolicy {
+    Fail,
+    Skip,
+}
+
+impl FromStr for InvalidPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(InvalidPolicy::Fail),
+            "skip" => Ok(InvalidPolicy::Skip),
+            _ => Err(format!(
+                "invalid record policy {} is not one of fail, skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Decides what happens to records that fail validation. Skipped records are written to the
+/// quarantine file, if any, with their record number and the reason they were skipped.
+pub struct InvalidRecords {
+    policy: InvalidPolicy,
+    quarantine_writer_opt: Option<csv::Writer<File>>,
+    max_invalid_opt: Option<u64>,
+    count: u64,
+    // the inputs, named in errors
+    source: String,
+}
+
+impl InvalidRecords {
+    pub fn new(
+        policy: InvalidPolicy,
+        quarantine_writer_opt: Option<csv::Writer<File>>,
+        max_invalid_opt: Option<u64>,
+        source: &str,
+    ) -> Result<Self, csv::Error> {
+        let quarantine_writer_opt = match quarantine_writer_opt {
+            Some(mut quarantine_writer) => {
+                quarantine_writer.write_record(vec!["record number", "read id", "reason"])?;
+                Some(quarantine_writer)
+            }
+            None => None,
+        };
+        Ok(InvalidRecords {
+            policy,
+            quarantine_writer_opt,
+            max_invalid_opt,
+            count: 0,
+            source: source.to_owned(),
+        })
+    }
+
+    /// Errors with the reason if invalid records fail the run, or once there are more than the
+    /// maximum number of them.
+    pub fn reject(&mut self, record_number: u64, id: &str, reason: &str) -> Result<(), String> {
+        if self.policy == InvalidPolicy::Fail {
+            return Err(format!(
+                "record {} ({}) of {} is invalid: {}",
+                record_number, id, self.source, reason
+            ));
+        }
+        self.count += 1;
+        if let Some(quarantine_writer) = self.quarantine_writer_opt.as_mut() {
+            quarantine_writer
+                .write_record(vec![&record_number.to_string(), id, reason])
+                .map_err(|err| err.to_string())?;
+        }
+        match self.max_invalid_opt {
+            Some(max_invalid) if self.count > max_invalid => Err(format!(
+                "more than {} invalid records, the last was record {} ({}) of {}: {}",
+                max_invalid, record_number, id, self.source, reason
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl Default for InvalidRecords {
+    fn default() -> Self {
+        InvalidRecords {
+            policy: InvalidPolicy::Fail,
+            quarantine_writer_opt: None,
+            max_invalid_opt: None,
+            count: 0,
+            source: "the input".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reject() {
+        assert_eq!(
+            InvalidRecords::default().reject(1, "id_a", "bad"),
+            Err("record 1 (id_a) of the input is invalid: bad".to_owned())
+        );
+
+        let dir = tempdir().unwrap();
+        let quarantine_path = dir.path().join("quarantine.csv");
+        let quarantine_writer = csv::Writer::from_path(&quarantine_path).unwrap();
+        let mut invalid_records = InvalidRecords::new(
+            InvalidPolicy::Skip,
+            Some(quarantine_writer),
+            Some(1),
+            "a.fq",
+        )
+        .unwrap();
+        assert_eq!(invalid_records.reject(2, "id_a", "bad"), Ok(()));
+        assert_eq!(
+            invalid_records.reject(5, "id_b", "worse"),
+            Err(
+                "more than 1 invalid records, the last was record 5 (id_b) of a.fq: worse"
+                    .to_owned()
+            )
+        );
+        assert_eq!(invalid_records.count(), 2);
+        drop(invalid_records);
+        assert_eq!(
+            std::fs::read_to_string(&quarantine_path).unwrap(),
+            "record number,read id,reason\n2,id_a,bad\n5,id_b,worse\n"
+        );
+    }
+}
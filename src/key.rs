@@ -0,0 +1,237 @@
+use bio::alphabets::dna;
+use std::any::Any;
+use std::borrow::Cow;
+
+use super::fastx;
+use super::paired::PairedRecord;
+use super::window::KeyWindow;
+
+/// Turns records into the bytes they are clustered on. Records with equal keys are duplicates.
+/// Pairs and groups are keyed member by member, where `member` is the position of a record in
+/// its pair or group.
+pub trait KeyExtractor {
+    /// Appends the key of `record` to `key`.
+    fn extract_key(&self, member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>);
+
+    /// Members left out of the key of their pair or group.
+    fn ignores(&self, _member: usize) -> bool {
+        false
+    }
+
+    fn key(&self, record: &dyn fastx::Record) -> Vec<u8> {
+        let mut key = Vec::new();
+        self.extract_key(0, record, &mut key);
+        key
+    }
+
+    /// Each member's key is preceded by its length, so keys of different members can't run into
+    /// each other.
+    fn group_key(&self, members: &[(usize, &dyn fastx::Record)]) -> Vec<u8> {
+        let mut key = Vec::new();
+        let mut member_key = Vec::new();
+        for (member, record) in members.iter() {
+            if self.ignores(*member) {
+                continue;
+            }
+            member_key.clear();
+            self.extract_key(*member, *record, &mut member_key);
+            key.extend_from_slice(&(member_key.len() as u64).to_le_bytes());
+            key.extend_from_slice(&member_key);
+        }
+        key
+    }
+}
+
+/// Keys a pair on r1 then r2, as members 0 and 1.
+pub fn pair_key<T: fastx::Record, E: KeyExtractor + ?Sized>(
+    extractor: &E,
+    record: &PairedRecord<T>,
+) -> Vec<u8> {
+    extractor.group_key(&[(0, record.r1()), (1, record.r2())])
+}
+
+impl KeyExtractor for KeyWindow {
+    fn extract_key(&self, _member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        key.extend_from_slice(self.extract(record.seq()));
+    }
+
+    fn ignores(&self, _member: usize) -> bool {
+        self.ignores_mate()
+    }
+}
+
+/// A key window per member, the last one applying to any further members. Members whose window
+/// has a length of zero are ignored.
+pub struct MateWindows(pub Vec<KeyWindow>);
+
+impl MateWindows {
+    fn window(&self, member: usize) -> &KeyWindow {
+        &self.0[std::cmp::min(member, self.0.len() - 1)]
+    }
+}
+
+impl KeyExtractor for MateWindows {
+    fn extract_key(&self, member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        key.extend_from_slice(self.window(member).extract(record.seq()));
+    }
+
+    fn ignores(&self, member: usize) -> bool {
+        self.window(member).ignores_mate()
+    }
+}
+
+/// The UMI appended to read IDs after the last `delimiter`, as UMI-tools and bcl2fastq do with
+/// `_` and `:`. IDs without the delimiter have an empty UMI.
+pub struct Umi {
+    pub delimiter: char,
+}
+
+impl KeyExtractor for Umi {
+    fn extract_key(&self, _member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        if let Some((_, umi)) = record.id().rsplit_once(self.delimiter) {
+            key.extend_from_slice(umi.as_bytes());
+        }
+    }
+}
+
+/// The 0-based `index`th field of the header, the read ID then the description, split on
+/// `delimiter` or on whitespace. Missing fields are empty.
+pub struct HeaderField {
+    pub delimiter_opt: Option<char>,
+    pub index: usize,
+}
+
+impl KeyExtractor for HeaderField {
+    fn extract_key(&self, _member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        let header = match record.desc() {
+            Some(desc) => Cow::Owned(format!("{} {}", record.id(), desc)),
+            None => Cow::Borrowed(record.id()),
+        };
+        let field_opt = match self.delimiter_opt {
+            Some(delimiter) => header.split(delimiter).nth(self.index),
+            None => header.split_whitespace().nth(self.index),
+        };
+        if let Some(field) = field_opt {
+            key.extend_from_slice(field.as_bytes());
+        }
+    }
+}
+
+/// Keys a record on the smaller of the keys of its sequence and of its reverse complement, so
+/// reads of either strand of a molecule are duplicates.
+pub struct CanonicalStrand<E: KeyExtractor>(pub E);
+
+impl<E: KeyExtractor> KeyExtractor for CanonicalStrand<E> {
+    fn extract_key(&self, member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        let mut forward = Vec::new();
+        self.0.extract_key(member, record, &mut forward);
+        let reverse_record = WithSequence::new(record, Cow::Owned(dna::revcomp(record.seq())));
+        let mut reverse = Vec::new();
+        self.0.extract_key(member, &reverse_record, &mut reverse);
+        key.extend_from_slice(std::cmp::min(&forward, &reverse));
+    }
+
+    fn ignores(&self, member: usize) -> bool {
+        self.0.ignores(member)
+    }
+}
+
+/// Keys a record on the keys of each extractor in turn, such as a UMI then a key window.
+pub struct Chain(pub Vec<Box<dyn KeyExtractor>>);
+
+impl KeyExtractor for Chain {
+    fn extract_key(&self, member: usize, record: &dyn fastx::Record, key: &mut Vec<u8>) {
+        for extractor in self.0.iter() {
+            if !extractor.ignores(member) {
+                extractor.extract_key(member, record, key);
+            }
+        }
+    }
+
+    fn ignores(&self, member: usize) -> bool {
+        self.0.iter().all(|extractor| extractor.ignores(member))
+    }
+}
+
+/// A record with another sequence, such as its reverse complement or one segment of it, for
+/// extractors to key on.
+pub struct WithSequence<'a> {
+    record: &'a dyn fastx::Record,
+    seq: Cow<'a, [u8]>,
+}
+
+impl<'a> WithSequence<'a> {
+    pub fn new(record: &'a dyn fastx::Record, seq: Cow<'a, [u8]>) -> Self {
+        WithSequence { record, seq }
+    }
+}
+
+impl<'a> fastx::Record for WithSequence<'a> {
+    fn id(&self) -> &str {
+        self.record.id()
+    }
+
+    fn desc(&self) -> Option<&str> {
+        self.record.desc()
+    }
+
+    fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    // qualities no longer line up with the sequence
+    fn qual(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn check(&self) -> Result<(), &str> {
+        self.record.check()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self.record.as_any()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bio::io::fasta;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_extractors() {
+        let record = fasta::Record::with_attrs("read_1:ACGT", Some("1:N:0 lane_2"), b"AACCGT");
+        let umi = Umi { delimiter: ':' };
+        assert_eq!(umi.key(&record), b"ACGT");
+        let field = HeaderField {
+            delimiter_opt: None,
+            index: 2,
+        };
+        assert_eq!(field.key(&record), b"lane_2");
+        let window = KeyWindow::from_str("start:1:3").unwrap();
+        let chain = Chain(vec![Box::new(umi), Box::new(window.clone())]);
+        assert_eq!(chain.key(&record), b"ACGTACC");
+        // the reverse complement is ACGGTT
+        assert_eq!(CanonicalStrand(window).key(&record), b"ACC");
+        let reverse = fasta::Record::with_attrs("read_2", None, b"ACGGTT");
+        assert_eq!(
+            CanonicalStrand(KeyWindow::prefix(None)).key(&reverse),
+            b"AACCGT"
+        );
+    }
+
+    #[test]
+    fn test_pair_key() {
+        let pair = PairedRecord::try_from((
+            fasta::Record::with_attrs("id_a", None, b"ACGT"),
+            fasta::Record::with_attrs("id_a", None, b"TTTT"),
+        ))
+        .unwrap();
+        let windows = MateWindows(vec![KeyWindow::prefix(Some(2)), KeyWindow::prefix(Some(0))]);
+        let mut expected = 2u64.to_le_bytes().to_vec();
+        expected.extend_from_slice(b"AC");
+        assert_eq!(pair_key(&windows, &pair), expected);
+    }
+}
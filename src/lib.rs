@@ -0,0 +1,22 @@
+//! Read deduplication shared by the `czid-dedup` executable and Rust callers. `clusters` holds
+//! the dedup engine, `key` the ways a record is turned into what it is clustered on and
+//! `membership` where the clusters each record joins are sent.
+
+pub mod alphabet;
+pub mod ambiguity;
+pub mod clusters;
+pub mod containment;
+pub mod fastx;
+pub mod format;
+pub mod group;
+pub mod invalid;
+pub mod key;
+pub mod membership;
+pub mod paired;
+pub mod position;
+pub mod read_structure;
+pub mod representative;
+pub mod rotation;
+pub mod sam;
+pub mod table;
+pub mod window;
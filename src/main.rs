@@ -1,12 +1,14 @@
-use bio::io::{fasta, fastq};
 use clap::{App, Arg};
-use simple_error;
+use std::borrow::Cow;
 use std::error::Error;
 use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
 
-mod clusters;
-mod fastx;
-mod paired;
+use czid_dedup::{
+    alphabet, ambiguity, clusters, containment, fastx, format, group, invalid, membership, paired,
+    position, read_structure, representative, rotation, sam, table, window,
+};
 
 macro_rules! box_result_error {
     ($result:expr) => {
@@ -29,85 +31,633 @@ macro_rules! box_bail {
     };
 }
 
-macro_rules! dedup {
-    ($fastx:tt, $fastx_type_r1:expr, $input_r1:expr, $output_r1:expr, $inputs:expr, $outputs:expr, $clusters:expr) => {{
-        let records_r1 = $fastx::Reader::from_file($input_r1).unwrap().records();
-        let writer_r1 = $fastx::Writer::to_file($output_r1).unwrap();
-        match ($inputs.next(), $outputs.next()) {
-            (Some(input_r2), Some(output_r2)) => {
-                let fastx_type_r2 = fastx::fastx_type(input_r2).unwrap();
-                if fastx_type_r2 != $fastx_type_r1 {
-                    let message = format!(
-                        "paired inputs have different file types r1: {}, r2: {}",
-                        $fastx_type_r1, fastx_type_r2
-                    );
-                    return Err(Box::new(simple_error::simple_error!(message)));
-                }
-                let records_r2 = $fastx::Reader::from_file(input_r2).unwrap().records();
-                let writer_r2 = $fastx::Writer::to_file(output_r2).unwrap();
-                let records = paired::PairedRecords::new(records_r1, records_r2);
-                pair(records, writer_r1, writer_r2, &mut $clusters)
+/// Hands a record that failed validation to the invalid record policy. `record_index` is
+/// 0-based.
+fn reject(
+    clusters: &mut clusters::Clusters,
+    record_index: usize,
+    id: &str,
+    reason: &str,
+) -> Result<(), simple_error::SimpleError> {
+    clusters
+        .reject(record_index as u64 + 1, id, reason)
+        .map_err(|err| simple_error::simple_error!(err))
+}
+
+fn single<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>, S: fastx::Writer<T>>(
+    records: R,
+    mut writer: S,
+    mut duplicate_writer_opt: Option<S>,
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    for (record_index, result) in records.enumerate() {
+        let record = box_bail!(result);
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), err));
+            continue;
+        }
+
+        let result = clusters.insert_single(&record);
+        if box_bail!(result).is_representative() {
+            box_bail!(writer.write_record(&record));
+        } else if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+            box_bail!(duplicate_writer.write_record(&record));
+        }
+    }
+    box_bail!(writer.finish());
+    if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+        box_bail!(duplicate_writer.finish());
+    }
+    Ok(())
+}
+
+/// Keeps the sequences that aren't exact substrings of longer ones, clustering the others with
+/// the sequence containing them. Every record is held in memory to index the sequences.
+fn contain<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>, S: fastx::Writer<T>>(
+    records: R,
+    mut writer: S,
+    mut duplicate_writer_opt: Option<S>,
+    fold_case: bool,
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    let mut valid_records = Vec::new();
+    for (record_index, result) in records.enumerate() {
+        let record = box_bail!(result);
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), err));
+            continue;
+        }
+        valid_records.push(record);
+    }
+
+    let seqs: Vec<Cow<[u8]>> = valid_records
+        .iter()
+        .map(|record| {
+            if fold_case {
+                Cow::Owned(record.seq().to_ascii_uppercase())
+            } else {
+                Cow::Borrowed(record.seq())
             }
-            (None, None) => single(records_r1, writer_r1, &mut $clusters),
-            _ => panic!("must have the same number of inputs and outputs"),
+        })
+        .collect();
+    let seqs: Vec<&[u8]> = seqs.iter().map(|seq| seq.as_ref()).collect();
+    let representatives = box_bail!(
+        containment::representatives(&seqs).map_err(|err| simple_error::simple_error!(err))
+    );
+    for (i, (record, representative)) in valid_records.iter().zip(representatives).enumerate() {
+        let representative_id = valid_records[representative].id();
+        let result = clusters.insert_assigned(record.id(), i, representative_id, representative);
+        if box_bail!(result).is_representative() {
+            box_bail!(writer.write_record(record));
+        } else if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+            box_bail!(duplicate_writer.write_record(record));
         }
-    }};
+    }
+    box_bail!(writer.finish());
+    if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+        box_bail!(duplicate_writer.finish());
+    }
+    Ok(())
 }
 
-fn single<
+fn pair<
     T: fastx::Record,
-    R: Iterator<Item = Result<T, std::io::Error>>,
+    R: Iterator<Item = Result<paired::PairedRecord<T>, std::io::Error>>,
     S: fastx::Writer<T>,
-    U: std::io::Write,
 >(
     records: R,
-    mut writer: S,
-    clusters: &mut clusters::Clusters<U>,
+    mut writers: paired::PairedWriter<S>,
+    mut duplicate_writers_opt: Option<paired::PairedWriter<S>>,
+    clusters: &mut clusters::Clusters,
 ) -> Result<(), Box<dyn Error>> {
-    for result in records {
+    for (record_index, result) in records.enumerate() {
         let record = box_bail!(result);
-        box_bail!(record
-            .check()
-            .map_err(|err| simple_error::simple_error!(err)));
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), &err));
+            continue;
+        }
 
-        let result = clusters.insert_single(&record);
-        if box_bail!(result) {
-            box_bail!(writer.write_record(&record));
+        let result = clusters.insert_pair(&record);
+        if box_bail!(result).is_representative() {
+            box_bail!(writers.write_pair(&record));
+        } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+            box_bail!(duplicate_writers.write_pair(&record));
         }
     }
+    box_bail!(writers.finish());
+    if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+        box_bail!(duplicate_writers.finish());
+    }
     Ok(())
 }
 
-fn pair<
+fn group<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>, S: fastx::Writer<T>>(
+    records: group::RecordGroups<T, R>,
+    mut writers: Vec<S>,
+    mut duplicate_writers_opt: Option<Vec<S>>,
+    key_members: &[usize],
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    for (record_index, result) in records.enumerate() {
+        let record = box_bail!(result);
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), &err));
+            continue;
+        }
+
+        let result = clusters.insert_group(&record, key_members);
+        let writers = if box_bail!(result).is_representative() {
+            &mut writers
+        } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+            duplicate_writers
+        } else {
+            continue;
+        };
+        let mates = record.members().len();
+        for (i, (writer, member)) in writers.iter_mut().zip(record.members()).enumerate() {
+            box_bail!(writer.write_mate(member, i, mates));
+        }
+    }
+    for writer in writers
+        .iter_mut()
+        .chain(duplicate_writers_opt.iter_mut().flatten())
+    {
+        box_bail!(writer.finish());
+    }
+    Ok(())
+}
+
+/// The first pass of drivers that read their records twice.
+fn insert_all<
+    T: fastx::Record,
+    R: Iterator<Item = Result<group::RecordGroup<T>, std::io::Error>>,
+>(
+    records: R,
+    key_members: &[usize],
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    for (record_index, result) in records.enumerate() {
+        let record = box_bail!(result);
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), &err));
+            continue;
+        }
+
+        box_bail!(clusters.insert_group(&record, key_members));
+    }
+    Ok(())
+}
+
+/// Writes every record, marking whether it is a duplicate, the read ID of its cluster's
+/// representative and the size of its cluster. Cluster sizes are only known once every record
+/// has been inserted, so the records are read twice.
+fn mark<
+    T: fastx::Record,
+    R: Iterator<Item = Result<group::RecordGroup<T>, std::io::Error>>,
+    F: Fn() -> Result<R, std::io::Error>,
+    S: fastx::Writer<T>,
+>(
+    open_records: F,
+    mut writers: Vec<S>,
+    key_members: &[usize],
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    insert_all(box_bail!(open_records()), key_members, clusters)?;
+
+    // skipped invalid records were not inserted so they don't count towards the index
+    let mut inserted_index = 0;
+    for result in box_bail!(open_records()) {
+        let record = box_bail!(result);
+        if record.check().is_err() {
+            continue;
+        }
+        // guaranteed to be present since every valid record was inserted in the first pass
+        let cluster = clusters.cluster_of_record(inserted_index).unwrap();
+        let mark = fastx::DuplicateMark {
+            duplicate: cluster.representative_index() != inserted_index,
+            representative_id: cluster.id(),
+            cluster_size: cluster.size(),
+        };
+        let mates = record.members().len();
+        for (i, member) in record.members().iter().enumerate() {
+            // with fewer writers than members the remaining members are interleaved
+            let writer_index = std::cmp::min(i, writers.len() - 1);
+            box_bail!(writers[writer_index].write_marked_mate(member, i, mates, &mark));
+        }
+        inserted_index += 1;
+    }
+    for writer in writers.iter_mut() {
+        box_bail!(writer.finish());
+    }
+    Ok(())
+}
+
+/// Writes the representative a policy chose for each cluster, and its other members to the
+/// duplicate writers if there are any. Representatives are only known once every record has been
+/// inserted, so the records are read twice.
+fn select<
+    T: fastx::Record,
+    R: Iterator<Item = Result<group::RecordGroup<T>, std::io::Error>>,
+    F: Fn() -> Result<R, std::io::Error>,
+    S: fastx::Writer<T>,
+>(
+    open_records: F,
+    mut writers: Vec<S>,
+    mut duplicate_writers_opt: Option<Vec<S>>,
+    key_members: &[usize],
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    insert_all(box_bail!(open_records()), key_members, clusters)?;
+
+    // skipped invalid records were not inserted so they don't count towards the index
+    let mut inserted_index = 0;
+    for result in box_bail!(open_records()) {
+        let record = box_bail!(result);
+        if record.check().is_err() {
+            continue;
+        }
+        // guaranteed to be present since every valid record was inserted in the first pass
+        let cluster = clusters.cluster_of_record(inserted_index).unwrap();
+        let representative = cluster.representative_index() == inserted_index;
+        inserted_index += 1;
+        let writers = if representative {
+            &mut writers
+        } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+            duplicate_writers
+        } else {
+            continue;
+        };
+        let mates = record.members().len();
+        for (i, member) in record.members().iter().enumerate() {
+            // with fewer writers than members the remaining members are interleaved
+            let writer_index = std::cmp::min(i, writers.len() - 1);
+            box_bail!(writers[writer_index].write_mate(member, i, mates));
+        }
+    }
+    for writer in writers
+        .iter_mut()
+        .chain(duplicate_writers_opt.iter_mut().flatten())
+    {
+        box_bail!(writer.finish());
+    }
+    Ok(())
+}
+
+/// Orphans are deduplicated as single-end reads, their duplicates going to the duplicates
+/// writer of their mate.
+fn resync_pair<
     T: fastx::Record,
     R: Iterator<Item = Result<T, std::io::Error>>,
     S: fastx::Writer<T>,
-    U: std::io::Write,
 >(
-    records: paired::PairedRecords<T, R>,
-    mut writer_r1: S,
-    mut writer_r2: S,
-    clusters: &mut clusters::Clusters<U>,
+    records: paired::ResyncedRecords<T, R>,
+    mut writers: paired::PairedWriter<S>,
+    mut singleton_writers: paired::PairedWriter<S>,
+    mut duplicate_writers_opt: Option<paired::PairedWriter<S>>,
+    clusters: &mut clusters::Clusters,
 ) -> Result<(), Box<dyn Error>> {
     for result in records {
-        let record = box_bail!(result);
+        match box_bail!(result) {
+            paired::ResyncedRecord::Paired(record, record_index) => {
+                if let Err(err) = record.check() {
+                    box_bail!(reject(clusters, record_index, record.id(), &err));
+                    continue;
+                }
+
+                let result = clusters.insert_pair(&record);
+                if box_bail!(result).is_representative() {
+                    box_bail!(writers.write_pair(&record));
+                } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+                    box_bail!(duplicate_writers.write_pair(&record));
+                }
+            }
+            paired::ResyncedRecord::OrphanR1(record, record_index) => {
+                if let Err(err) = record.check() {
+                    let reason = format!("r1: {}", err);
+                    box_bail!(reject(clusters, record_index, record.id(), &reason));
+                    continue;
+                }
 
-        box_bail!(record
-            .check()
-            .map_err(|err| simple_error::simple_error!(&err)));
+                let result = clusters.insert_orphan(&record, 0);
+                if box_bail!(result).is_representative() {
+                    box_bail!(singleton_writers.write_r1(&record));
+                } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+                    box_bail!(duplicate_writers.write_r1(&record));
+                }
+            }
+            paired::ResyncedRecord::OrphanR2(record, record_index) => {
+                if let Err(err) = record.check() {
+                    let reason = format!("r2: {}", err);
+                    box_bail!(reject(clusters, record_index, record.id(), &reason));
+                    continue;
+                }
 
-        let result = clusters.insert_pair(&record);
-        if box_bail!(result) {
-            box_bail!(writer_r1.write_record(record.r1()));
-            box_bail!(writer_r2.write_record(record.r2()));
+                let result = clusters.insert_orphan(&record, 1);
+                if box_bail!(result).is_representative() {
+                    box_bail!(singleton_writers.write_r2(&record));
+                } else if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+                    box_bail!(duplicate_writers.write_r2(&record));
+                }
+            }
+        }
+    }
+    box_bail!(writers.finish());
+    box_bail!(singleton_writers.finish());
+    if let Some(duplicate_writers) = duplicate_writers_opt.as_mut() {
+        box_bail!(duplicate_writers.finish());
+    }
+    Ok(())
+}
+
+type GroupedRecords =
+    Box<dyn Iterator<Item = Result<group::RecordGroup<Box<dyn fastx::Record>>, std::io::Error>>>;
+
+/// The inputs and outputs of a sequence-keyed run, read and written in any format.
+struct Dedup<'a> {
+    inputs: Vec<&'a str>,
+    outputs: Vec<&'a str>,
+    singleton_outputs_opt: Option<(&'a str, &'a str)>,
+    duplicate_outputs_opt: Option<Vec<&'a str>>,
+    input_format: &'static dyn format::Format,
+    output_format: &'static dyn format::Format,
+    writer_options: format::WriterOptions,
+    interleaved: bool,
+    key_members_opt: Option<Vec<usize>>,
+    mark_duplicates: bool,
+    // whether a representative policy other than the first seen record is set
+    select_representatives: bool,
+    alphabet_opt: Option<alphabet::Alphabet>,
+    fold_case: bool,
+    // the parts of reads checked against the maximum N fraction, one per mate or one for all
+    key_windows: Vec<window::KeyWindow>,
+    max_n_fraction_opt: Option<f64>,
+    containment: bool,
+}
+
+impl<'a> Dedup<'a> {
+    fn key_window(&self, member: usize) -> &window::KeyWindow {
+        &self.key_windows[std::cmp::min(member, self.key_windows.len() - 1)]
+    }
+
+    fn open_records(&self, input_index: usize) -> Result<format::Records, std::io::Error> {
+        let mut records = self
+            .input_format
+            .reader(Path::new(self.inputs[input_index]))?;
+        if let Some(alphabet) = self.alphabet_opt {
+            records = Box::new(records.map(move |result| {
+                result.map(|record| {
+                    Box::new(alphabet::AlphabetRecord::new(record, alphabet))
+                        as Box<dyn fastx::Record>
+                })
+            }));
+        }
+        if let Some(max_n_fraction) = self.max_n_fraction_opt {
+            // interleaved inputs alternate between r1 and r2, and only members of the key are
+            // checked
+            let members = if self.interleaved {
+                vec![0, 1]
+            } else {
+                vec![input_index]
+            };
+            let key_windows: Vec<Option<window::KeyWindow>> = members
+                .into_iter()
+                .map(|member| match self.key_members_opt.as_ref() {
+                    Some(key_members) if !key_members.contains(&member) => None,
+                    _ => Some(self.key_window(member).clone()),
+                })
+                .collect();
+            records = Box::new(records.enumerate().map(move |(i, result)| {
+                result.map(|record| match &key_windows[i % key_windows.len()] {
+                    Some(key_window) => Box::new(ambiguity::NFilterRecord::new(
+                        record,
+                        key_window,
+                        max_n_fraction,
+                    )) as Box<dyn fastx::Record>,
+                    None => record,
+                })
+            }));
+        }
+        Ok(records)
+    }
+
+    fn open_writer(&self, output: &str) -> Result<format::RecordWriter, std::io::Error> {
+        self.output_format.writer(
+            Path::new(output),
+            Path::new(self.inputs[0]),
+            &self.writer_options,
+        )
+    }
+
+    fn open_writers(&self, outputs: &[&str]) -> Result<Vec<format::RecordWriter>, std::io::Error> {
+        outputs
+            .iter()
+            .map(|output| self.open_writer(output))
+            .collect()
+    }
+
+    fn run(&self, clusters: &mut clusters::Clusters) -> Result<(), Box<dyn Error>> {
+        let writers = box_bail!(self.open_writers(&self.outputs));
+        let duplicate_writers_opt = match self.duplicate_outputs_opt.as_ref() {
+            Some(duplicate_outputs) => Some(box_bail!(self.open_writers(duplicate_outputs))),
+            None => None,
+        };
+
+        // both need every record inserted before any is written
+        if self.mark_duplicates || self.select_representatives {
+            let mates = if self.interleaved {
+                2
+            } else {
+                self.inputs.len()
+            };
+            let key_members = self
+                .key_members_opt
+                .clone()
+                .unwrap_or_else(|| (0..mates).collect());
+            let open_records = || -> Result<GroupedRecords, std::io::Error> {
+                let mut records = (0..self.inputs.len())
+                    .map(|input_index| self.open_records(input_index))
+                    .collect::<Result<Vec<format::Records>, _>>()?;
+                if self.interleaved {
+                    let records = paired::InterleavedRecords::new(records.remove(0))
+                        .with_name(self.inputs[0]);
+                    Ok(Box::new(
+                        records.map(|result| result.map(group::RecordGroup::from)),
+                    ))
+                } else {
+                    Ok(Box::new(
+                        group::RecordGroups::new(records).with_names(&self.inputs),
+                    ))
+                }
+            };
+            if self.mark_duplicates {
+                return mark(open_records, writers, &key_members, clusters);
+            }
+            return select(
+                open_records,
+                writers,
+                duplicate_writers_opt,
+                &key_members,
+                clusters,
+            );
+        }
+
+        let mut records = box_bail!((0..self.inputs.len())
+            .map(|input_index| self.open_records(input_index))
+            .collect::<Result<Vec<format::Records>, _>>());
+        if let Some(key_members) = self.key_members_opt.as_ref() {
+            let records = group::RecordGroups::new(records).with_names(&self.inputs);
+            return group(
+                records,
+                writers,
+                duplicate_writers_opt,
+                key_members,
+                clusters,
+            );
+        }
+
+        match (records.len(), self.singleton_outputs_opt) {
+            (1, _) if self.interleaved => {
+                let records =
+                    paired::InterleavedRecords::new(records.remove(0)).with_name(self.inputs[0]);
+                let writers = paired::PairedWriter::from_writers(writers);
+                let duplicate_writers_opt =
+                    duplicate_writers_opt.map(paired::PairedWriter::from_writers);
+                pair(records, writers, duplicate_writers_opt, clusters)
+            }
+            (2, Some((singleton_output_r1, singleton_output_r2))) => {
+                let singleton_writer_r1 = box_bail!(self.open_writer(singleton_output_r1));
+                let singleton_writer_r2 = box_bail!(self.open_writer(singleton_output_r2));
+                let singleton_writers =
+                    paired::PairedWriter::new(singleton_writer_r1, Some(singleton_writer_r2));
+                let records_r2 = records.remove(1);
+                let records = paired::ResyncedRecords::new(records.remove(0), records_r2)
+                    .with_names(self.inputs[0], self.inputs[1]);
+                let writers = paired::PairedWriter::from_writers(writers);
+                let duplicate_writers_opt =
+                    duplicate_writers_opt.map(paired::PairedWriter::from_writers);
+                resync_pair(
+                    records,
+                    writers,
+                    singleton_writers,
+                    duplicate_writers_opt,
+                    clusters,
+                )
+            }
+            (2, None) => {
+                let records_r2 = records.remove(1);
+                let records = paired::PairedRecords::new(records.remove(0), records_r2)
+                    .with_names(self.inputs[0], self.inputs[1]);
+                let writers = paired::PairedWriter::from_writers(writers);
+                let duplicate_writers_opt =
+                    duplicate_writers_opt.map(paired::PairedWriter::from_writers);
+                pair(records, writers, duplicate_writers_opt, clusters)
+            }
+            (_, Some(_)) => Err(Box::new(simple_error::simple_error!(
+                "resync requires paired inputs"
+            ))),
+            _ => {
+                // presence guarunteed by clap
+                let writer = writers.into_iter().next().unwrap();
+                let duplicate_writer_opt = duplicate_writers_opt
+                    .and_then(|duplicate_writers| duplicate_writers.into_iter().next());
+                if self.containment {
+                    contain(
+                        records.remove(0),
+                        writer,
+                        duplicate_writer_opt,
+                        self.fold_case,
+                        clusters,
+                    )
+                } else {
+                    single(records.remove(0), writer, duplicate_writer_opt, clusters)
+                }
+            }
+        }
+    }
+}
+
+/// Clusters aligned templates on library, strand and the unclipped 5' positions of their mates.
+/// Mates are found by read name anywhere in the input, so it may be coordinate sorted, and the
+/// input is read twice. Secondary and supplementary alignments follow their primary alignment.
+fn dedup_position(
+    input: &str,
+    output: &str,
+    duplicate_output_opt: Option<&str>,
+    mark_duplicates: bool,
+    clusters: &mut clusters::Clusters,
+) -> Result<(), Box<dyn Error>> {
+    use fastx::{Record, Writer};
+
+    let reader = box_bail!(sam::Reader::from_file(input));
+    let header = reader.header().clone();
+    let format = reader.format();
+    let templates = box_bail!(position::Templates::from_records(
+        reader.located_records(Path::new(input)),
+        &header.libraries()
+    ));
+    let mut writer = box_bail!(sam::Writer::to_file(output, &header, format));
+    let mut duplicate_writer_opt = match duplicate_output_opt {
+        Some(duplicate_output) => Some(box_bail!(sam::Writer::to_file(
+            duplicate_output,
+            &header,
+            format
+        ))),
+        None => None,
+    };
+
+    let mut duplicates = Vec::new();
+    for template in templates.iter() {
+        let result = clusters.insert_position(&template.key(), template.name());
+        duplicates.push(!box_bail!(result).is_representative());
+    }
+
+    for (record_index, result) in box_bail!(sam::Reader::from_file(input))
+        .located_records(Path::new(input))
+        .enumerate()
+    {
+        let record = box_bail!(result);
+        if let Err(err) = record.check() {
+            box_bail!(reject(clusters, record_index, record.id(), err));
+            continue;
+        }
+
+        // only secondary or supplementary alignments can be missing a template
+        let template_index_opt = templates.index_of(record.id());
+        let duplicate = template_index_opt
+            .map(|template_index| duplicates[template_index])
+            .unwrap_or(false);
+        if mark_duplicates {
+            let template_index = match template_index_opt {
+                Some(template_index) => template_index,
+                None => {
+                    box_bail!(writer.write_record(&record));
+                    continue;
+                }
+            };
+            // guaranteed to be present since every template was inserted
+            let cluster = clusters.cluster_of_record(template_index as u64).unwrap();
+            let mark = fastx::DuplicateMark {
+                duplicate,
+                representative_id: cluster.id(),
+                cluster_size: cluster.size(),
+            };
+            box_bail!(writer.write_marked_record(&record, &mark));
+        } else if !duplicate {
+            box_bail!(writer.write_record(&record));
+        } else if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+            box_bail!(duplicate_writer.write_record(&record));
         }
     }
+    box_bail!(writer.finish());
+    if let Some(duplicate_writer) = duplicate_writer_opt.as_mut() {
+        box_bail!(duplicate_writer.finish());
+    }
     Ok(())
 }
 
 fn run_dedup<T: Into<std::ffi::OsString> + Clone, R: IntoIterator<Item = T>>(
     args: R,
-) -> Result<clusters::Clusters<File>, Box<dyn Error>> {
+) -> Result<clusters::Clusters<'static>, Box<dyn Error>> {
     let matches = App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
@@ -119,7 +669,6 @@ fn run_dedup<T: Into<std::ffi::OsString> + Clone, R: IntoIterator<Item = T>>(
                 .help("Input FASTQ")
                 .multiple(true)
                 .min_values(1)
-                .max_values(2)
                 .takes_value(true)
                 .required(true),
         )
@@ -130,7 +679,6 @@ fn run_dedup<T: Into<std::ffi::OsString> + Clone, R: IntoIterator<Item = T>>(
                 .help("Output deduped FASTQ")
                 .multiple(true)
                 .min_values(1)
-                .max_values(2)
                 .takes_value(true)
                 .required(true),
         )
@@ -147,6 +695,177 @@ fn run_dedup<T: Into<std::ffi::OsString> + Clone, R: IntoIterator<Item = T>>(
                 .help("Output cluster size file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("cluster-format")
+                .long("cluster-format")
+                .help("Format of the cluster and cluster size files")
+                .takes_value(true)
+                .possible_values(&["csv", "tsv", "jsonl"])
+                .default_value("csv"),
+        )
+        .arg(
+            Arg::with_name("cluster-compression")
+                .long("cluster-compression")
+                .help("Compression of the cluster and cluster size files")
+                .takes_value(true)
+                .possible_values(&["none", "gzip", "zstd"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("duplicates-output")
+                .long("duplicates-output")
+                .help("Output FASTQ for removed duplicates, one per deduped output")
+                .multiple(true)
+                .min_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mark-duplicates")
+                .long("mark-duplicates")
+                .help("Write every read, tagging descriptions with duplicate status (DUP), representative read id (RID) and cluster size (CS)")
+                .conflicts_with_all(&["duplicates-output", "resync"]),
+        )
+        .arg(
+            Arg::with_name("resync")
+                .long("resync")
+                .help("Pair mates by read ID instead of by position, writing reads without a mate to --singleton-outputs")
+                .requires("singleton-outputs"),
+        )
+        .arg(
+            Arg::with_name("singleton-outputs")
+                .long("singleton-outputs")
+                .help("Output deduped FASTQ for r1 and r2 reads without a mate")
+                .multiple(true)
+                .min_values(2)
+                .max_values(2)
+                .takes_value(true)
+                .requires("resync"),
+        )
+        .arg(
+            Arg::with_name("interleaved")
+                .long("interleaved")
+                .help("Read pairs from a single interleaved input, writing them interleaved to one output or split across two")
+                .conflicts_with("resync"),
+        )
+        .arg(
+            Arg::with_name("key-members")
+                .long("key-members")
+                .help("Comma separated, 1-based positions of the inputs whose reads make up the dedup key [default: all inputs]")
+                .takes_value(true)
+                .use_delimiter(true)
+                .conflicts_with_all(&["resync", "interleaved"]),
+        )
+        .arg(
+            Arg::with_name("read-structure")
+                .long("read-structure")
+                .help("Read structure of each input, e.g. 16B12U and T, made of cell barcode (B), UMI (U), template (T) and skipped (S) segments")
+                .multiple(true)
+                .takes_value(true)
+                .conflicts_with("resync"),
+        )
+        .arg(
+            Arg::with_name("cell-stats-output")
+                .long("cell-stats-output")
+                .help("Output per-cell duplication statistics file")
+                .takes_value(true)
+                .requires("read-structure"),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .help("Format of the deduped outputs, one of fasta, fastq, sam or bam [default: the input format]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fill-quality")
+                .long("fill-quality")
+                .help("Quality character given to every base of reads converted to FASTQ from FASTA")
+                .takes_value(true)
+                .default_value("I"),
+        )
+        .arg(
+            Arg::with_name("alphabet")
+                .long("alphabet")
+                .help("Check that sequences are dna or protein, ignoring case when comparing them")
+                .takes_value(true)
+                .possible_values(&["dna", "protein"]),
+        )
+        .arg(
+            Arg::with_name("representative")
+                .long("representative")
+                .help("Which member of each cluster is kept, one of first, quality (highest mean base quality), longest or smallest-id [default: first]")
+                .takes_value(true)
+                .possible_values(&["first", "quality", "longest", "smallest-id"])
+                .conflicts_with_all(&["resync", "containment", "by-position"]),
+        )
+        .arg(
+            Arg::with_name("fold-case")
+                .long("fold-case")
+                .help("Ignore case when comparing sequences, so soft-masked bases match"),
+        )
+        .arg(
+            Arg::with_name("n-wildcard")
+                .long("n-wildcard")
+                .help("Let an N in a read match any base of the first representative it matches")
+                .conflicts_with_all(&["read-structure", "circular", "containment", "by-position"]),
+        )
+        .arg(
+            Arg::with_name("max-n-fraction")
+                .long("max-n-fraction")
+                .help("Reads whose key has a larger fraction of N calls are invalid, see --on-invalid")
+                .takes_value(true)
+                .conflicts_with("by-position"),
+        )
+        .arg(
+            Arg::with_name("containment")
+                .long("containment")
+                .help("Cluster FASTA sequences with the longer sequence they are an exact substring of")
+                .conflicts_with_all(&["prefix-length", "key-window", "trim-poly", "key-members", "read-structure", "mark-duplicates", "resync", "interleaved", "by-position"]),
+        )
+        .arg(
+            Arg::with_name("circular")
+                .long("circular")
+                .help("Cluster circular sequences on their lexicographically minimal rotation")
+                .conflicts_with_all(&["prefix-length", "key-window", "trim-poly", "key-members", "read-structure", "containment", "by-position"]),
+        )
+        .arg(
+            Arg::with_name("both-strands")
+                .long("both-strands")
+                .help("Cluster circular DNA with the rotations of its reverse complement too")
+                .requires("circular"),
+        )
+        .arg(
+            Arg::with_name("line-width")
+                .long("line-width")
+                .help("Wrap the sequences of FASTA outputs over lines of at most this many bases")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("by-position")
+                .long("by-position")
+                .help("Cluster aligned SAM/BAM reads on library, strand and unclipped 5' positions instead of sequence")
+                .conflicts_with_all(&["prefix-length", "key-window", "trim-poly", "key-members", "read-structure", "output-format", "alphabet"]),
+        )
+        .arg(
+            Arg::with_name("on-invalid")
+                .long("on-invalid")
+                .help("What to do with records that fail validation, fail the run or skip them")
+                .takes_value(true)
+                .possible_values(&["fail", "skip"])
+                .default_value("fail"),
+        )
+        .arg(
+            Arg::with_name("quarantine-output")
+                .long("quarantine-output")
+                .help("Output file listing the skipped invalid records and why they were skipped")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-invalid")
+                .long("max-invalid")
+                .help("Fail the run once more than this many invalid records were skipped")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("prefix-length")
                 .short("l")
@@ -154,66 +873,425 @@ fn run_dedup<T: Into<std::ffi::OsString> + Clone, R: IntoIterator<Item = T>>(
                 .help("Length of the prefix to consider")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("key-window")
+                .long("key-window")
+                .help("Part of each read to consider, offset bases in from its start or end, e.g. start:6:50 or end:0:30, one for every mate or one per mate where a length of 0 ignores the mate")
+                .multiple(true)
+                .takes_value(true)
+                .conflicts_with("prefix-length"),
+        )
+        .arg(
+            Arg::with_name("trim-poly")
+                .long("trim-poly")
+                .help("Bases whose trailing runs are trimmed before the key is taken, e.g. G or GA")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-poly-length")
+                .long("min-poly-length")
+                .help("Shortest trailing run trimmed by --trim-poly")
+                .takes_value(true)
+                .default_value("10"),
+        )
         .get_matches_from(args);
 
     // presence guarunteed by clap
-    let mut inputs = matches.values_of("inputs").unwrap();
-    let mut outputs = matches.values_of("deduped-outputs").unwrap();
+    let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+    let outputs: Vec<&str> = matches.values_of("deduped-outputs").unwrap().collect();
     let cluster_output_opt = matches.value_of("cluster-output");
     let cluster_size_output_opt = matches.value_of("cluster-size-output");
+    // possible values are checked by clap
+    let cluster_format = table::TableFormat::from_str(matches.value_of("cluster-format").unwrap())?;
+    let cluster_compression =
+        table::Compression::from_str(matches.value_of("cluster-compression").unwrap())?;
+    let cell_stats_output_opt = matches.value_of("cell-stats-output");
+    let singleton_outputs_opt = matches.values_of("singleton-outputs").map(|mut outputs| {
+        // exactly two values guarunteed by clap
+        (outputs.next().unwrap(), outputs.next().unwrap())
+    });
+    let duplicate_outputs_opt: Option<Vec<&str>> = matches
+        .values_of("duplicates-output")
+        .map(|duplicate_outputs| duplicate_outputs.collect());
+    let interleaved = matches.is_present("interleaved");
+    let mark_duplicates = matches.is_present("mark-duplicates");
+    let by_position = matches.is_present("by-position");
+    let containment = matches.is_present("containment");
+    let circular_opt = if matches.is_present("both-strands") {
+        Some(rotation::Strands::Both)
+    } else if matches.is_present("circular") {
+        Some(rotation::Strands::Forward)
+    } else {
+        None
+    };
+    // default guarunteed by clap
+    let fill_quality = match matches.value_of("fill-quality").unwrap().as_bytes() {
+        [fill_quality] if (b'!'..=b'~').contains(fill_quality) => *fill_quality,
+        _ => {
+            return Err(Box::new(simple_error::simple_error!(
+                "fill quality must be a single character from ! to ~"
+            )))
+        }
+    };
     let prefix_length_opt = matches
         .value_of("prefix-length")
         .map(|n| n.parse::<usize>().unwrap());
-    let input_r1 = inputs.next().unwrap();
-    let output_r1 = outputs.next().unwrap();
-
-    let bytes = File::open(input_r1).unwrap().metadata().unwrap().len() as usize;
-    // 400 is based on the bytes per record of an example file, should be reasonable
-    let mut clusters =
-        clusters::Clusters::from_file(cluster_output_opt, prefix_length_opt, bytes / 400).unwrap();
-
-    match fastx::fastx_type(input_r1).unwrap() {
-        fastx::FastxType::Fasta => dedup!(
-            fasta,
-            fastx::FastxType::Fasta,
-            input_r1,
-            output_r1,
-            inputs,
-            outputs,
-            clusters
-        ),
-        fastx::FastxType::Fastq => dedup!(
-            fastq,
-            fastx::FastxType::Fastq,
-            input_r1,
-            output_r1,
-            inputs,
-            outputs,
-            clusters
-        ),
-        fastx::FastxType::Invalid => Err(Box::new(simple_error::simple_error!(
-            "input file is not a valid FASTA or FASTQ file"
-        )) as Box<dyn Error>),
-    }?;
-
-    if let Some(cluster_sizes_output) = cluster_size_output_opt {
-        let mut cluster_sizes_writer = csv::Writer::from_path(cluster_sizes_output)?;
-        clusters.write_sizes(&mut cluster_sizes_writer)?;
+    let mut key_windows = match matches.values_of("key-window") {
+        Some(key_windows) => box_bail!(key_windows
+            .map(window::KeyWindow::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| simple_error::simple_error!(err))),
+        None => vec![window::KeyWindow::prefix(prefix_length_opt)],
+    };
+    if key_windows
+        .iter()
+        .all(|key_window| key_window.ignores_mate())
+    {
+        return Err(Box::new(simple_error::simple_error!(
+            "key windows must not ignore every mate"
+        )));
     }
-    Ok(clusters)
-}
-
-fn main() {
-    match run_dedup(std::env::args()) {
-        Err(err) => println!("{}", err.to_string()),
-        Ok(info) => {
-            println!(
-                "duplicates:   {:width$}",
-                info.duplicate_records(),
-                width = 16
-            );
-            println!("unique reads: {:width$}", info.unique_records(), width = 16);
+    if let Some(bases) = matches.value_of("trim-poly") {
+        if bases.is_empty() || !bases.bytes().all(|base| b"ACGTacgt".contains(&base)) {
+            let message = format!("poly bases {} are not made of A, C, G and T", bases);
+            return Err(Box::new(simple_error::simple_error!(message)));
+        }
+        // default guarunteed by clap
+        let min_poly_length = match matches
+            .value_of("min-poly-length")
+            .unwrap()
+            .parse::<usize>()
+        {
+            Ok(min_poly_length) if min_poly_length > 0 => min_poly_length,
+            _ => {
+                return Err(Box::new(simple_error::simple_error!(
+                    "min poly length must be a positive number"
+                )))
+            }
+        };
+        let poly_trim = window::PolyTrim::new(bases.as_bytes(), min_poly_length);
+        key_windows = key_windows
+            .into_iter()
+            .map(|key_window| key_window.with_poly_trim(poly_trim.clone()))
+            .collect();
+    }
+    let alphabet_opt =
+        match matches.value_of("alphabet") {
+            Some(alphabet) => Some(box_bail!(alphabet::Alphabet::from_str(alphabet)
+                .map_err(|err| simple_error::simple_error!(err)))),
+            None => None,
+        };
+    let max_n_fraction_opt = match matches.value_of("max-n-fraction") {
+        Some(n) => match n.parse::<f64>() {
+            Ok(max_n_fraction) if (0.0..=1.0).contains(&max_n_fraction) => Some(max_n_fraction),
+            _ => {
+                let message = format!("max N fraction {} is not a number from 0 to 1", n);
+                return Err(Box::new(simple_error::simple_error!(message)));
+            }
+        },
+        None => None,
+    };
+    // the first record is kept without reading the inputs twice
+    let representative_policy_opt = match matches.value_of("representative") {
+        Some("first") | None => None,
+        Some(name) => Some(box_bail!(
+            representative::by_name(name).map_err(|err| simple_error::simple_error!(err))
+        )),
+    };
+    // alphabets imply ignoring case
+    let fold_case = matches.is_present("fold-case") || alphabet_opt.is_some();
+    let line_width_opt = match matches.value_of("line-width") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(line_width) if line_width > 0 => Some(line_width),
+            _ => {
+                let message = format!("line width {} is not a positive number", n);
+                return Err(Box::new(simple_error::simple_error!(message)));
+            }
+        },
+        None => None,
+    };
+    // default guarunteed by clap
+    let invalid_policy = box_bail!(invalid::InvalidPolicy::from_str(
+        matches.value_of("on-invalid").unwrap()
+    )
+    .map_err(|err| simple_error::simple_error!(err)));
+    let max_invalid_opt = match matches.value_of("max-invalid") {
+        Some(n) => Some(box_bail!(n.parse::<u64>().map_err(|_| {
+            simple_error::simple_error!(format!("max invalid {} is not a number", n))
+        }))),
+        None => None,
+    };
+    if invalid_policy == invalid::InvalidPolicy::Fail
+        && (matches.is_present("quarantine-output") || max_invalid_opt.is_some())
+    {
+        return Err(Box::new(simple_error::simple_error!(
+            "quarantine output and max invalid require --on-invalid skip"
+        )));
+    }
+    let input_format = box_bail!(format::detect(inputs[0]));
+    let output_format = match matches.value_of("output-format") {
+        Some(name) => match format::by_name(name) {
+            Some(output_format) => output_format,
+            None => {
+                let message = format!(
+                    "unknown output format {}, expected one of {}",
+                    name,
+                    format::names().join(", ")
+                );
+                return Err(Box::new(simple_error::simple_error!(message)));
+            }
+        },
+        None => input_format,
+    };
+    if line_width_opt.is_some() && output_format.name() != "fasta" {
+        return Err(Box::new(simple_error::simple_error!(
+            "line width only applies to FASTA outputs"
+        )));
+    }
+    if by_position && (!matches!(input_format.name(), "sam" | "bam") || outputs.len() > 1) {
+        return Err(Box::new(simple_error::simple_error!(
+            "position mode takes a single SAM/BAM input and output"
+        )));
+    }
+    if containment && (input_format.name() != "fasta" || inputs.len() > 1) {
+        return Err(Box::new(simple_error::simple_error!(
+            "containment mode takes a single FASTA input"
+        )));
+    }
+    if circular_opt == Some(rotation::Strands::Both)
+        && alphabet_opt == Some(alphabet::Alphabet::Protein)
+    {
+        return Err(Box::new(simple_error::simple_error!(
+            "both strands only applies to DNA"
+        )));
+    }
+    // paired SAM/BAM holds both mates in one file
+    let interleaved = interleaved || box_bail!(input_format.interleaved(Path::new(inputs[0])));
+    if circular_opt.is_some() && (interleaved || inputs.len() > 1) {
+        return Err(Box::new(simple_error::simple_error!(
+            "circular mode takes a single unpaired input"
+        )));
+    }
+    if interleaved && (singleton_outputs_opt.is_some() || matches.is_present("key-members")) {
+        return Err(Box::new(simple_error::simple_error!(
+            "resync and key members are not supported for interleaved inputs"
+        )));
+    }
+    if interleaved && inputs.len() > 1 {
+        return Err(Box::new(simple_error::simple_error!(
+            "interleaved mode takes a single input"
+        )));
+    }
+    if singleton_outputs_opt.is_some() && inputs.len() > 2 {
+        return Err(Box::new(simple_error::simple_error!(
+            "resync mode takes at most two inputs"
+        )));
+    }
+    // pairs without explicit key members keep the two input pipeline
+    let key_members_opt = match matches.values_of("key-members") {
+        Some(key_members) => Some(box_bail!(key_members
+            .map(|n| match n.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= inputs.len() => Ok(n - 1),
+                _ => Err(simple_error::simple_error!(format!(
+                    "key member {} is not the position of an input",
+                    n
+                ))),
+            })
+            .collect::<Result<Vec<usize>, _>>())),
+        None if inputs.len() > 2 => Some((0..inputs.len()).collect()),
+        None => None,
+    };
+    if let Some(duplicate_outputs) = duplicate_outputs_opt.as_ref() {
+        if duplicate_outputs.len() != outputs.len() {
+            return Err(Box::new(simple_error::simple_error!(
+                "must have the same number of duplicates outputs and deduped outputs"
+            )));
+        }
+    }
+    if !interleaved && inputs.len() != outputs.len() {
+        return Err(Box::new(simple_error::simple_error!(
+            "must have the same number of inputs and outputs"
+        )));
+    }
+    let read_structures_opt = match matches.values_of("read-structure") {
+        Some(read_structures) => Some(box_bail!(read_structures
+            .map(read_structure::ReadStructure::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| simple_error::simple_error!(err)))),
+        None => None,
+    };
+    if let Some(read_structures) = read_structures_opt.as_ref() {
+        let mates = if interleaved { 2 } else { inputs.len() };
+        if read_structures.len() != mates {
+            let message = format!(
+                "expected {} read structures, one per mate, found {}",
+                mates,
+                read_structures.len()
+            );
+            return Err(Box::new(simple_error::simple_error!(message)));
+        }
+    }
+
+    if key_windows.len() > 1 {
+        let mates = if interleaved { 2 } else { inputs.len() };
+        if key_windows.len() != mates {
+            let message = format!(
+                "expected 1 key window or {}, one per mate, found {}",
+                mates,
+                key_windows.len()
+            );
+            return Err(Box::new(simple_error::simple_error!(message)));
+        }
+        // orphans are keyed on their own mate alone
+        if singleton_outputs_opt.is_some()
+            && key_windows
+                .iter()
+                .any(|key_window| key_window.ignores_mate())
+        {
+            return Err(Box::new(simple_error::simple_error!(
+                "resync can't ignore a mate"
+            )));
+        }
+    }
+
+    let bytes = File::open(inputs[0]).unwrap().metadata().unwrap().len() as usize;
+    // 400 is based on the bytes per record of an example file, should be reasonable
+    let mut clusters = clusters::Clusters::new(prefix_length_opt, bytes / 400);
+    if let Some(cluster_output) = cluster_output_opt {
+        clusters = clusters.with_membership_sink(Box::new(membership::TableSink::create(
+            cluster_output,
+            cluster_format,
+            cluster_compression,
+        )?));
+    }
+    if let Some(read_structures) = read_structures_opt {
+        clusters = clusters.with_read_structures(read_structures);
+    }
+    let select_representatives = representative_policy_opt.is_some();
+    if mark_duplicates || select_representatives {
+        clusters = clusters.retain_assignments();
+    }
+    clusters = clusters.with_key_windows(key_windows.clone());
+    if fold_case {
+        clusters = clusters.with_case_folding();
+    }
+    if let Some(representative_policy) = representative_policy_opt {
+        clusters = clusters.with_representative_policy(representative_policy);
+    }
+    if matches.is_present("n-wildcard") {
+        clusters = clusters.with_n_wildcard();
+    }
+    if let Some(strands) = circular_opt {
+        clusters = clusters.with_circular(strands);
+    }
+    let quarantine_writer_opt = match matches.value_of("quarantine-output") {
+        Some(quarantine_output) => Some(csv::Writer::from_path(quarantine_output)?),
+        None => None,
+    };
+    let invalid_records = invalid::InvalidRecords::new(
+        invalid_policy,
+        quarantine_writer_opt,
+        max_invalid_opt,
+        &inputs.join(", "),
+    )?;
+    clusters = clusters.with_invalid_records(invalid_records);
+
+    for (i, input) in inputs.iter().enumerate().skip(1) {
+        let name = box_bail!(format::detect(input)).name();
+        if name != input_format.name() {
+            let message = if key_members_opt.is_some() {
+                format!(
+                    "grouped inputs have different file types input 1: {}, input {}: {}",
+                    input_format.name(),
+                    i + 1,
+                    name
+                )
+            } else {
+                format!(
+                    "paired inputs have different file types r1: {}, r2: {}",
+                    input_format.name(),
+                    name
+                )
+            };
+            return Err(Box::new(simple_error::simple_error!(message)));
+        }
+    }
+
+    if by_position {
+        let duplicate_output_opt =
+            duplicate_outputs_opt.and_then(|duplicate_outputs| duplicate_outputs.first().copied());
+        dedup_position(
+            inputs[0],
+            outputs[0],
+            duplicate_output_opt,
+            mark_duplicates,
+            &mut clusters,
+        )?;
+    } else {
+        let dedup = Dedup {
+            inputs,
+            outputs,
+            singleton_outputs_opt,
+            duplicate_outputs_opt,
+            input_format,
+            output_format,
+            writer_options: format::WriterOptions {
+                fill_quality,
+                line_width_opt,
+            },
+            interleaved,
+            key_members_opt,
+            mark_duplicates,
+            select_representatives,
+            alphabet_opt,
+            fold_case,
+            key_windows,
+            max_n_fraction_opt,
+            containment,
+        };
+        dedup.run(&mut clusters)?;
+    }
+    clusters.finish()?;
+
+    if let Some(cluster_sizes_output) = cluster_size_output_opt {
+        let mut cluster_sizes_writer =
+            table::TableWriter::create(cluster_sizes_output, cluster_format, cluster_compression)?;
+        clusters.write_sizes(&mut cluster_sizes_writer)?;
+        cluster_sizes_writer.finish()?;
+    }
+    if let Some(cell_stats_output) = cell_stats_output_opt {
+        let mut cell_stats_writer = table::TableWriter::create(
+            cell_stats_output,
+            table::TableFormat::Csv,
+            table::Compression::None,
+        )?;
+        clusters.write_cell_stats(&mut cell_stats_writer)?;
+        cell_stats_writer.finish()?;
+    }
+    Ok(clusters)
+}
+
+fn main() {
+    match run_dedup(std::env::args()) {
+        Err(err) => println!("{}", err),
+        Ok(info) => {
+            println!(
+                "duplicates:   {:width$}",
+                info.duplicate_records(),
+                width = 16
+            );
+            println!("unique reads: {:width$}", info.unique_records(), width = 16);
             println!("total reads:  {:width$}", info.total_records(), width = 16);
+            if info.invalid_records() > 0 {
+                println!(
+                    "invalid reads: {:width$}",
+                    info.invalid_records(),
+                    width = 15
+                );
+            }
+            if info.cells() > 0 {
+                println!("cells:        {:width$}", info.cells(), width = 16);
+            }
         }
     }
 }
@@ -222,9 +1300,14 @@ fn main() {
 mod test {
     use super::*;
 
-    use bio::io::fastq;
+    use bio::io::{fasta, fastq};
     use rand::Rng;
-    use tempfile::tempdir;
+    use std::io::Read;
+    use tempfile::{tempdir, TempDir};
+
+    fn temp_path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().unwrap().to_string()
+    }
 
     fn random_seq(len: usize) -> Vec<u8> {
         const CHARSET: &[u8] = b"ACTG";
@@ -264,7 +1347,7 @@ mod test {
             "-c",
             &cluster_path,
         ];
-        let result = run_dedup(&args).expect("don't break");
+        let result = run_dedup(args).expect("don't break");
         assert_eq!(result.total_records(), 1);
         dir.close().expect("don't break");
     }
@@ -319,7 +1402,7 @@ mod test {
             "-c",
             &cluster_path,
         ];
-        let result = run_dedup(&args).expect("don't break");
+        let result = run_dedup(args).expect("don't break");
         assert_eq!(result.total_records(), 1);
         dir.close().expect("don't break");
     }
@@ -376,7 +1459,7 @@ mod test {
             "-c",
             &cluster_path,
         ];
-        let result = run_dedup(&args);
+        let result = run_dedup(args);
         let message = result
             .err()
             .expect("should error on mismatched inputs")
@@ -387,4 +1470,1025 @@ mod test {
         );
         dir.close().expect("don't break");
     }
+
+    #[test]
+    fn test_run_dedup_paired_different_ids() {
+        let dir = tempdir().unwrap();
+        let (input_path_r1, input_path_r2) = (
+            temp_path(&dir, "input-r1.fasta"),
+            temp_path(&dir, "input-r2.fasta"),
+        );
+        let (output_path_r1, output_path_r2) = (
+            temp_path(&dir, "output-r1.fasta"),
+            temp_path(&dir, "output-r2.fasta"),
+        );
+
+        {
+            let mut writer_r1 = fasta::Writer::to_file(&input_path_r1).expect("don't break");
+            let mut writer_r2 = fasta::Writer::to_file(&input_path_r2).expect("don't break");
+            let seq = random_seq(20);
+            for (id_r1, id_r2) in [("id_a", "id_a"), ("id_b", "id_c")].iter() {
+                writer_r1.write(id_r1, None, &seq).expect("don't break");
+                writer_r2.write(id_r2, None, &seq).expect("don't break");
+            }
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path_r1,
+            "-i",
+            &input_path_r2,
+            "-o",
+            &output_path_r1,
+            "-o",
+            &output_path_r2,
+        ];
+        let message = run_dedup(args)
+            .err()
+            .expect("should error on different read IDs")
+            .to_string();
+        assert_eq!(
+            message,
+            format!(
+                "read pair had different read IDs: (id_b, id_c) at record 2 of {} and {}",
+                input_path_r1, input_path_r2
+            )
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_resync() {
+        let dir = tempdir().unwrap();
+        let (input_path_r1, input_path_r2) = (
+            temp_path(&dir, "input-r1.fasta"),
+            temp_path(&dir, "input-r2.fasta"),
+        );
+        let (output_path_r1, output_path_r2) = (
+            temp_path(&dir, "output-r1.fasta"),
+            temp_path(&dir, "output-r2.fasta"),
+        );
+        let (singleton_path_r1, singleton_path_r2) = (
+            temp_path(&dir, "singleton-r1.fasta"),
+            temp_path(&dir, "singleton-r2.fasta"),
+        );
+
+        {
+            let mut writer_r1 = fasta::Writer::to_file(&input_path_r1).expect("don't break");
+            let mut writer_r2 = fasta::Writer::to_file(&input_path_r2).expect("don't break");
+            let seq = random_seq(20);
+            writer_r1.write("id_a", None, &seq).expect("don't break");
+            writer_r1.write("id_b", None, &seq).expect("don't break");
+            writer_r1.write("id_c", None, &seq).expect("don't break");
+            writer_r2.write("id_c", None, &seq).expect("don't break");
+            writer_r2.write("id_a", None, &seq).expect("don't break");
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path_r1,
+            "-i",
+            &input_path_r2,
+            "-o",
+            &output_path_r1,
+            "-o",
+            &output_path_r2,
+            "--resync",
+            "--singleton-outputs",
+            &singleton_path_r1,
+            &singleton_path_r2,
+        ];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.total_records(), 3);
+        assert_eq!(result.unique_records(), 2);
+
+        let singleton_ids: Vec<String> = fasta::Reader::from_file(&singleton_path_r1)
+            .expect("don't break")
+            .records()
+            .map(|record| record.expect("don't break").id().to_owned())
+            .collect();
+        assert_eq!(singleton_ids, vec!["id_b"]);
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_interleaved() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fastq");
+        let output_path = temp_path(&dir, "output.fastq");
+        let (output_path_r1, output_path_r2) = (
+            temp_path(&dir, "output-r1.fastq"),
+            temp_path(&dir, "output-r2.fastq"),
+        );
+
+        {
+            let mut writer = fastq::Writer::to_file(&input_path).expect("don't break");
+            let seq_r1 = random_seq(20);
+            let seq_r2 = random_seq(20);
+            writer
+                .write("id_a", None, &seq_r1, &seq_r1)
+                .expect("don't break");
+            writer
+                .write("id_a", None, &seq_r2, &seq_r2)
+                .expect("don't break");
+            writer
+                .write("id_b", None, &seq_r1, &seq_r1)
+                .expect("don't break");
+            writer
+                .write("id_b", None, &seq_r2, &seq_r2)
+                .expect("don't break");
+        }
+
+        let args = [
+            "executable",
+            "--interleaved",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+        ];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.total_records(), 2);
+        assert_eq!(result.unique_records(), 1);
+        let interleaved_count = fastq::Reader::from_file(&output_path)
+            .expect("don't break")
+            .records()
+            .count();
+        assert_eq!(interleaved_count, 2);
+
+        let args = [
+            "executable",
+            "--interleaved",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path_r1,
+            "-o",
+            &output_path_r2,
+        ];
+        run_dedup(args).expect("don't break");
+        let split_count = fastq::Reader::from_file(&output_path_r2)
+            .expect("don't break")
+            .records()
+            .count();
+        assert_eq!(split_count, 1);
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_index_reads() {
+        let dir = tempdir().unwrap();
+        let names = ["r1", "r2", "i1", "i2"];
+        let inputs: Vec<String> = names
+            .iter()
+            .map(|name| temp_path(&dir, &format!("input-{}.fastq", name)))
+            .collect();
+        let outputs: Vec<String> = names
+            .iter()
+            .map(|name| temp_path(&dir, &format!("output-{}.fastq", name)))
+            .collect();
+
+        {
+            let seqs: Vec<Vec<u8>> = names.iter().map(|_| random_seq(20)).collect();
+            for (input, seq) in inputs.iter().zip(seqs.iter()) {
+                let mut writer = fastq::Writer::to_file(input).expect("don't break");
+                writer.write("id_a", None, seq, seq).expect("don't break");
+                writer.write("id_b", None, seq, seq).expect("don't break");
+                // only the index reads differ, and are too ambiguous to be part of a key
+                let index = if input.contains("input-i") {
+                    vec![b'N'; 20]
+                } else {
+                    seq.clone()
+                };
+                writer
+                    .write("id_c", None, &index, &index)
+                    .expect("don't break");
+            }
+        }
+
+        let mut args = vec!["executable".to_string()];
+        for (input, output) in inputs.iter().zip(outputs.iter()) {
+            args.extend(vec!["-i".to_string(), input.clone()]);
+            args.extend(vec!["-o".to_string(), output.clone()]);
+        }
+        let result = run_dedup(args.clone()).expect("don't break");
+        assert_eq!(result.total_records(), 3);
+        assert_eq!(result.unique_records(), 2);
+
+        args.extend(vec!["--key-members".to_string(), "1,2".to_string()]);
+        let result = run_dedup(args.clone()).expect("don't break");
+        assert_eq!(result.unique_records(), 1);
+        drop(result);
+
+        // index reads left out of the key aren't checked for Ns
+        args.extend(vec!["--max-n-fraction".to_string(), "0.5".to_string()]);
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.invalid_records(), 0);
+        assert_eq!(result.unique_records(), 1);
+        let index_count = fastq::Reader::from_file(&outputs[3])
+            .expect("don't break")
+            .records()
+            .count();
+        assert_eq!(index_count, 1);
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_read_structure() {
+        let dir = tempdir().unwrap();
+        let (input_path_r1, input_path_r2) = (
+            temp_path(&dir, "input-r1.fastq"),
+            temp_path(&dir, "input-r2.fastq"),
+        );
+        let (output_path_r1, output_path_r2) = (
+            temp_path(&dir, "output-r1.fastq"),
+            temp_path(&dir, "output-r2.fastq"),
+        );
+        let cell_stats_path = temp_path(&dir, "cell-stats.csv");
+
+        {
+            let mut writer_r1 = fastq::Writer::to_file(&input_path_r1).expect("don't break");
+            let mut writer_r2 = fastq::Writer::to_file(&input_path_r2).expect("don't break");
+            let cdna = random_seq(20);
+            for (id, barcode_umi) in [
+                ("id_a", b"AAAACC"),
+                ("id_b", b"AAAACC"),
+                ("id_c", b"AAAAGG"),
+            ] {
+                writer_r1
+                    .write(id, None, barcode_umi, barcode_umi)
+                    .expect("don't break");
+                writer_r2
+                    .write(id, None, &cdna, &cdna)
+                    .expect("don't break");
+            }
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path_r1,
+            "-i",
+            &input_path_r2,
+            "-o",
+            &output_path_r1,
+            "-o",
+            &output_path_r2,
+            "--read-structure",
+            "4B2U",
+            "T",
+            "--cell-stats-output",
+            &cell_stats_path,
+        ];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.unique_records(), 2);
+        assert_eq!(
+            std::fs::read_to_string(&cell_stats_path).expect("don't break"),
+            "cell barcode,total reads,unique reads,duplicate reads\nAAAA,3,2,1\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_duplicates_output() {
+        let dir = tempdir().unwrap();
+        let (input_path_r1, input_path_r2) = (
+            temp_path(&dir, "input-r1.fasta"),
+            temp_path(&dir, "input-r2.fasta"),
+        );
+        let (output_path_r1, output_path_r2) = (
+            temp_path(&dir, "output-r1.fasta"),
+            temp_path(&dir, "output-r2.fasta"),
+        );
+        let (duplicates_path_r1, duplicates_path_r2) = (
+            temp_path(&dir, "duplicates-r1.fasta"),
+            temp_path(&dir, "duplicates-r2.fasta"),
+        );
+
+        {
+            let mut writer_r1 = fasta::Writer::to_file(&input_path_r1).expect("don't break");
+            let mut writer_r2 = fasta::Writer::to_file(&input_path_r2).expect("don't break");
+            let seq = random_seq(20);
+            for id in ["id_a", "id_b", "id_c"].iter() {
+                writer_r1.write(id, None, &seq).expect("don't break");
+                writer_r2.write(id, None, &seq).expect("don't break");
+            }
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path_r1,
+            "-i",
+            &input_path_r2,
+            "-o",
+            &output_path_r1,
+            "-o",
+            &output_path_r2,
+            "--duplicates-output",
+            &duplicates_path_r1,
+            &duplicates_path_r2,
+        ];
+        run_dedup(args).expect("don't break");
+        for duplicates_path in [&duplicates_path_r1, &duplicates_path_r2].iter() {
+            let duplicate_ids: Vec<String> = fasta::Reader::from_file(duplicates_path)
+                .expect("don't break")
+                .records()
+                .map(|record| record.expect("don't break").id().to_owned())
+                .collect();
+            assert_eq!(duplicate_ids, vec!["id_b", "id_c"]);
+        }
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_mark_duplicates() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fastq");
+        let output_path = temp_path(&dir, "output.fastq");
+
+        {
+            let mut writer = fastq::Writer::to_file(&input_path).expect("don't break");
+            let seq_a = random_seq(20);
+            let seq_b = random_seq(20);
+            writer
+                .write("id_a", Some("sample=1"), &seq_a, &seq_a)
+                .expect("don't break");
+            writer
+                .write("id_b", None, &seq_b, &seq_b)
+                .expect("don't break");
+            writer
+                .write("id_c", None, &seq_a, &seq_a)
+                .expect("don't break");
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--mark-duplicates",
+        ];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.duplicate_records(), 1);
+
+        let descs: Vec<String> = fastq::Reader::from_file(&output_path)
+            .expect("don't break")
+            .records()
+            .map(|record| record.expect("don't break").desc().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            descs,
+            vec![
+                "sample=1 DUP:N RID:id_a CS:2",
+                "DUP:N RID:id_b CS:1",
+                "DUP:Y RID:id_a CS:2"
+            ]
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_sam() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.sam");
+        let output_path = temp_path(&dir, "output.sam");
+        let marked_output_path = temp_path(&dir, "marked.sam");
+
+        let seq_a = String::from_utf8(random_seq(20)).unwrap();
+        let seq_b = String::from_utf8(random_seq(20)).unwrap();
+        let qual = "I".repeat(20);
+        let mut sam = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+        for (id, seq_r1) in [("id_a", &seq_a), ("id_b", &seq_b), ("id_c", &seq_a)].iter() {
+            for (flag, seq) in [(77, seq_r1), (141, &&seq_b)].iter() {
+                sam.push_str(&format!(
+                    "{}\t{}\t*\t0\t0\t*\t*\t0\t0\t{}\t{}\tRG:Z:rg1\n",
+                    id, flag, seq, qual
+                ));
+            }
+        }
+        std::fs::write(&input_path, sam).expect("don't break");
+
+        let args = ["executable", "-i", &input_path, "-o", &output_path];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.total_records(), 3);
+        assert_eq!(result.duplicate_records(), 1);
+        let output = std::fs::read_to_string(&output_path).expect("don't break");
+        let ids: Vec<&str> = output
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["id_a", "id_a", "id_b", "id_b"]);
+        assert!(output
+            .lines()
+            .skip(1)
+            .all(|line| line.ends_with("\tRG:Z:rg1")));
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &marked_output_path,
+            "--mark-duplicates",
+        ];
+        run_dedup(args).expect("don't break");
+        let flags: Vec<u16> = std::fs::read_to_string(&marked_output_path)
+            .expect("don't break")
+            .lines()
+            .skip(1)
+            .map(|line| line.split('\t').nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(flags, vec![77, 141, 77, 141, 77 | 0x400, 141 | 0x400]);
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_by_position() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.sam");
+        let output_path = temp_path(&dir, "output.sam");
+        let cluster_size_path = temp_path(&dir, "cluster-sizes.csv");
+
+        // b is a's pair soft-clipped by 2 bases, c starts at the same position on the other strand
+        let sam = "@SQ\tSN:chr1\tLN:1000\n\
+            a\t99\tchr1\t101\t60\t10M\t=\t201\t110\tACGTACGTAC\t*\n\
+            b\t99\tchr1\t103\t60\t2S8M\t=\t201\t108\tTTGTACGTAC\t*\n\
+            c\t115\tchr1\t101\t60\t10M\t=\t201\t110\tACGTACGTAC\t*\n\
+            a\t147\tchr1\t201\t60\t10M\t=\t101\t-110\tACGTACGTAC\t*\n\
+            b\t147\tchr1\t201\t60\t10M\t=\t103\t-108\tACGTACGTAC\t*\n\
+            c\t179\tchr1\t201\t60\t10M\t=\t101\t-110\tACGTACGTAC\t*\n";
+        std::fs::write(&input_path, sam).expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--by-position",
+            "--mark-duplicates",
+            "--cluster-size-output",
+            &cluster_size_path,
+        ];
+        let result = run_dedup(args).expect("don't break");
+        assert_eq!(result.total_records(), 3);
+        assert_eq!(result.duplicate_records(), 1);
+        let duplicate_ids: Vec<String> = std::fs::read_to_string(&output_path)
+            .expect("don't break")
+            .lines()
+            .skip(1)
+            .map(|line| line.split('\t').collect::<Vec<&str>>())
+            .filter(|fields| fields[1].parse::<u16>().unwrap() & 0x400 != 0)
+            .map(|fields| fields[0].to_owned())
+            .collect();
+        assert_eq!(duplicate_ids, vec!["b", "b"]);
+        assert_eq!(
+            std::fs::read_to_string(&cluster_size_path).expect("don't break"),
+            "representative read id,cluster size\na,2\nc,1\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_output_format() {
+        let dir = tempdir().unwrap();
+        let fasta_path = temp_path(&dir, "input.fasta");
+        let fastq_path = temp_path(&dir, "output.fastq");
+        let converted_fasta_path = temp_path(&dir, "output.fasta");
+
+        let seq = random_seq(20);
+        {
+            let mut writer = fasta::Writer::to_file(&fasta_path).expect("don't break");
+            writer.write("id_a", None, &seq).expect("don't break");
+            writer.write("id_b", None, &seq).expect("don't break");
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &fasta_path,
+            "-o",
+            &fastq_path,
+            "--output-format",
+            "fastq",
+            "--fill-quality",
+            "5",
+        ];
+        run_dedup(args).expect("don't break");
+        let records: Vec<fastq::Record> = fastq::Reader::from_file(&fastq_path)
+            .expect("don't break")
+            .records()
+            .map(|record| record.expect("don't break"))
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq(), &seq[..]);
+        assert_eq!(records[0].qual(), &[b'5'; 20][..]);
+
+        let args = [
+            "executable",
+            "-i",
+            &fastq_path,
+            "-o",
+            &converted_fasta_path,
+            "--output-format",
+            "fasta",
+        ];
+        run_dedup(args).expect("don't break");
+        assert_eq!(
+            std::fs::read_to_string(&converted_fasta_path).expect("don't break"),
+            format!(">id_a\n{}\n", String::from_utf8(seq).unwrap())
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_fastq_to_bam() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fastq");
+        let output_path = temp_path(&dir, "output.bam");
+
+        let seq = random_seq(20);
+        {
+            let mut writer = fastq::Writer::to_file(&input_path).expect("don't break");
+            writer
+                .write("id_a", Some("sample=1"), &seq, &seq)
+                .expect("don't break");
+            writer.write("id_b", None, &seq, &seq).expect("don't break");
+        }
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--output-format",
+            "bam",
+        ];
+        run_dedup(args).expect("don't break");
+        let output_format = format::detect(&output_path).expect("don't break");
+        assert_eq!(output_format.name(), "bam");
+        let records: Vec<sam::Record> = sam::Reader::from_file(&output_path)
+            .expect("don't break")
+            .records()
+            .map(|record| record.expect("don't break"))
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(fastx::Record::id(&records[0]), "id_a");
+        assert_eq!(fastx::Record::qual(&records[0]), Some(&seq[..]));
+        assert_eq!(records[0].flag(), sam::FLAG_UNMAPPED);
+        assert_eq!(records[0].string_tag(b"CO"), Some("sample=1".to_owned()));
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_on_invalid_skip() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fastq");
+        let output_path = temp_path(&dir, "output.fastq");
+        let quarantine_path = temp_path(&dir, "quarantine.csv");
+
+        std::fs::write(
+            &input_path,
+            "@id_a\nACGT\n+\nIIII\n@id_b\nACGT\n+\nIIIIII\n@id_c\nACGT\n+\nIIII\n",
+        )
+        .expect("don't break");
+
+        let args = ["executable", "-i", &input_path, "-o", &output_path];
+        let err = run_dedup(args).err().expect("don't break");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "record 2 (id_b) of {} is invalid: Unequal length of sequence an qualities.",
+                input_path
+            )
+        );
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--on-invalid",
+            "skip",
+            "--quarantine-output",
+            &quarantine_path,
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.invalid_records(), 1);
+        assert_eq!(clusters.total_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&quarantine_path).expect("don't break"),
+            "record number,read id,reason\n2,id_b,Unequal length of sequence an qualities.\n"
+        );
+
+        // a short quality must not run on into the next record's header
+        std::fs::write(
+            &input_path,
+            "@id_a\nACGT\n+\nIIII\n@id_b\nACGT\n+\nII\n@id_c\nACGT\n+\nIIII\n",
+        )
+        .expect("don't break");
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.invalid_records(), 1);
+        assert_eq!(clusters.total_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&quarantine_path).expect("don't break"),
+            "record number,read id,reason\n2,id_b,Unequal length of sequence an qualities.\n"
+        );
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--on-invalid",
+            "skip",
+            "--max-invalid",
+            "0",
+        ];
+        assert!(run_dedup(args).is_err());
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_protein() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.faa");
+        let output_path = temp_path(&dir, "output.faa");
+        let cluster_path = temp_path(&dir, "clusters.csv");
+
+        std::fs::write(
+            &input_path,
+            ">id_a\nMKVLAAGX*\n>id_b\nmkvlaagx*\n>id_c\nMKV1\n>id_d\nMKW\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--alphabet",
+            "protein",
+            "--on-invalid",
+            "skip",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.invalid_records(), 1);
+        assert_eq!(clusters.unique_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&output_path).expect("don't break"),
+            ">id_a\nMKVLAAGX*\n>id_d\nMKW\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id\nid_a,id_a\nid_a,id_b\nid_d,id_d\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_containment() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+        let cluster_path = temp_path(&dir, "clusters.csv");
+
+        std::fs::write(
+            &input_path,
+            ">id_a\nCGTA\n>id_b\nAACGTACC\n>id_c\nTTTT\n>id_d\nGGAACGTACCGG\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--containment",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&output_path).expect("don't break"),
+            ">id_c\nTTTT\n>id_d\nGGAACGTACCGG\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id\nid_d,id_a\nid_d,id_b\nid_c,id_c\nid_d,id_d\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_circular() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+        let cluster_path = temp_path(&dir, "clusters.csv");
+
+        // id_b is id_a started 4 bases in, id_c is the reverse complement of id_a started 2 bases in
+        std::fs::write(
+            &input_path,
+            ">id_a\nGATTACAGG\n>id_b\nACAGGGATT\n>id_c\nTCCCTGTAA\n>id_d\nTTTT\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--circular",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 3);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id,rotation offset,strand\n\
+             id_a,id_a,0,+\nid_a,id_b,5,+\nid_c,id_c,0,+\nid_d,id_d,0,+\n"
+        );
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--circular",
+            "--both-strands",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&output_path).expect("don't break"),
+            ">id_a\nGATTACAGG\n>id_d\nTTTT\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id,rotation offset,strand\n\
+             id_a,id_a,0,+\nid_a,id_b,5,+\nid_a,id_c,7,-\nid_d,id_d,0,+\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_n_normalisation() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+        let quarantine_path = temp_path(&dir, "quarantine.csv");
+
+        std::fs::write(
+            &input_path,
+            ">id_a\nACGTACGT\n>id_b\nacgtNcgt\n>id_c\nNNNNACGT\n>id_d\nTTTTACGT\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--fold-case",
+            "--n-wildcard",
+            "--max-n-fraction",
+            "0.25",
+            "--on-invalid",
+            "skip",
+            "--quarantine-output",
+            &quarantine_path,
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+        assert_eq!(clusters.invalid_records(), 1);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&output_path).expect("don't break"),
+            ">id_a\nACGTACGT\n>id_d\nTTTTACGT\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&quarantine_path).expect("don't break"),
+            "record number,read id,reason\n\
+             3,id_c,\"key has 4 Ns in 8 bases, more than the maximum fraction 0.25\"\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_key_window() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+
+        // id_b has a different primer and id_c a poly-G tail
+        std::fs::write(
+            &input_path,
+            ">id_a\nAAAACGTACGT\n>id_b\nTTTACGTACGT\n>id_c\nAAAACGTACGTGGGGG\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--key-window",
+            "end:0:8",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--key-window",
+            "end:0:8",
+            "--trim-poly",
+            "G",
+            "--min-poly-length",
+            "4",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 1);
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_key_window_per_mate() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+        let cluster_path = temp_path(&dir, "clusters.csv");
+
+        std::fs::write(
+            &input_path,
+            ">id_a\nACGTAA\n>id_a\nTTTT\n>id_b\nACGTCC\n>id_b\nGGGG\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "--interleaved",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--key-window",
+            "start:0:4",
+            "start:0:0",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 1);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id,key mates\nid_a,id_a,1\nid_a,id_b,1\n"
+        );
+
+        let args = [
+            "executable",
+            "--interleaved",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "--key-window",
+            "start:0:4",
+            "start:0:0",
+            "end:0:0",
+        ];
+        assert_eq!(
+            run_dedup(args).err().expect("should fail").to_string(),
+            "expected 1 key window or 2, one per mate, found 3"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_representative() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fastq");
+        let output_path = temp_path(&dir, "output.fastq");
+        let duplicate_path = temp_path(&dir, "duplicates.fastq");
+        let cluster_path = temp_path(&dir, "clusters.csv");
+
+        std::fs::write(
+            &input_path,
+            "@id_a\nACGT\n+\n5555\n@id_b\nACGT\n+\nIIII\n@id_c\nTTTT\n+\nIIII\n",
+        )
+        .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--duplicates-output",
+            &duplicate_path,
+            "--representative",
+            "quality",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+        drop(clusters);
+        assert_eq!(
+            std::fs::read_to_string(&output_path).expect("don't break"),
+            "@id_b\nACGT\n+\nIIII\n@id_c\nTTTT\n+\nIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&duplicate_path).expect("don't break"),
+            "@id_a\nACGT\n+\n5555\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&cluster_path).expect("don't break"),
+            "representative read id,read id\nid_b,id_a\nid_b,id_b\nid_c,id_c\n"
+        );
+        dir.close().expect("don't break");
+    }
+
+    #[test]
+    fn test_run_dedup_cluster_format() {
+        let dir = tempdir().unwrap();
+        let input_path = temp_path(&dir, "input.fasta");
+        let output_path = temp_path(&dir, "output.fasta");
+        let cluster_path = temp_path(&dir, "clusters.jsonl");
+        let cluster_size_path = temp_path(&dir, "cluster_sizes.tsv.gz");
+
+        std::fs::write(&input_path, ">id_a,1\nACGT\n>id_b\nACGT\n>id_c\nTTTT\n")
+            .expect("don't break");
+
+        let args = [
+            "executable",
+            "-i",
+            &input_path,
+            "-o",
+            &output_path,
+            "-c",
+            &cluster_path,
+            "--cluster-size-output",
+            &cluster_size_path,
+            "--cluster-format",
+            "jsonl",
+            "--cluster-compression",
+            "gzip",
+        ];
+        let clusters = run_dedup(args).expect("don't break");
+        assert_eq!(clusters.unique_records(), 2);
+        let read = |path: &str| {
+            let mut contents = String::new();
+            format::open(Path::new(path))
+                .expect("don't break")
+                .0
+                .read_to_string(&mut contents)
+                .expect("don't break");
+            contents
+        };
+        assert_eq!(
+            read(&cluster_path),
+            "{\"representative read id\":\"id_a,1\",\"read id\":\"id_a,1\"}\n\
+             {\"representative read id\":\"id_a,1\",\"read id\":\"id_b\"}\n\
+             {\"representative read id\":\"id_c\",\"read id\":\"id_c\"}\n"
+        );
+        assert_eq!(
+            read(&cluster_size_path),
+            "{\"representative read id\":\"id_a,1\",\"cluster size\":2}\n\
+             {\"representative read id\":\"id_c\",\"cluster size\":1}\n"
+        );
+        dir.close().expect("don't break");
+    }
 }
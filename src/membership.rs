@@ -0,0 +1,231 @@
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+use super::rotation::Rotation;
+use super::table::{Compression, Field, TableFile, TableFormat, TableWriter};
+
+/// What became of an inserted record.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The record represents its cluster. `replaced_opt` is the representative it took over from,
+    /// if a representative policy preferred it to one inserted earlier.
+    Representative { replaced_opt: Option<String> },
+    /// The record is a duplicate of `representative_id`, the representative so far.
+    Duplicate { representative_id: String },
+}
+
+impl Outcome {
+    pub fn is_representative(&self) -> bool {
+        matches!(self, Outcome::Representative { .. })
+    }
+}
+
+/// The optional columns of the cluster output, set once every option is known.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Columns {
+    pub rotation: bool,
+    pub key_mates: bool,
+}
+
+impl Columns {
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec!["representative read id", "read id"];
+        if self.rotation {
+            names.extend(vec!["rotation offset", "strand"]);
+        }
+        if self.key_mates {
+            names.push("key mates");
+        }
+        names
+    }
+}
+
+/// A record's place in its cluster. Circular members have how they turn into their
+/// representative, and records keyed on mates with their own windows have the 0-based mates that
+/// made up the key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Membership<'a> {
+    pub representative_id: &'a str,
+    pub id: &'a str,
+    pub rotation_opt: Option<Rotation>,
+    pub key_mates_opt: Option<&'a [usize]>,
+}
+
+impl<'a> Membership<'a> {
+    /// The fields of the cluster output row. Key mates are 1-based and joined with `+`.
+    pub fn fields(&self) -> Vec<Field<'a>> {
+        let mut fields = vec![Field::from(self.representative_id), Field::from(self.id)];
+        if let Some(rotation) = self.rotation_opt {
+            fields.push(Field::Number(rotation.offset as u64));
+            fields.push(Field::from(if rotation.reverse { "-" } else { "+" }));
+        }
+        if let Some(key_mates) = self.key_mates_opt {
+            let key_mates: Vec<String> = key_mates
+                .iter()
+                .map(|mate| (mate + 1).to_string())
+                .collect();
+            fields.push(Field::Text(Cow::Owned(key_mates.join("+"))));
+        }
+        fields
+    }
+}
+
+/// Receives cluster membership as records are clustered. With a representative policy members
+/// arrive once every record is inserted, as representatives may change until then.
+pub trait MembershipSink {
+    /// Called once, before any member.
+    fn begin(&mut self, columns: &Columns) -> io::Result<()>;
+
+    fn member(&mut self, membership: &Membership) -> io::Result<()>;
+
+    /// Called once every member has been received.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: MembershipSink + ?Sized> MembershipSink for &mut S {
+    fn begin(&mut self, columns: &Columns) -> io::Result<()> {
+        (**self).begin(columns)
+    }
+
+    fn member(&mut self, membership: &Membership) -> io::Result<()> {
+        (**self).member(membership)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Writes membership as a CSV, TSV or JSON Lines table.
+pub struct TableSink<W: io::Write> {
+    table_writer: TableWriter<W>,
+    // ends the output once every member is written
+    finish_fn: fn(&mut W) -> io::Result<()>,
+}
+
+impl<W: io::Write> TableSink<W> {
+    pub fn new(writer: W, format: TableFormat) -> Self {
+        TableSink {
+            table_writer: TableWriter::new(writer, format),
+            finish_fn: W::flush,
+        }
+    }
+}
+
+impl TableSink<TableFile> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: TableFormat,
+        compression: Compression,
+    ) -> io::Result<Self> {
+        Ok(TableSink {
+            table_writer: TableWriter::create(path, format, compression)?,
+            finish_fn: TableFile::finish,
+        })
+    }
+}
+
+impl<W: io::Write> MembershipSink for TableSink<W> {
+    fn begin(&mut self, columns: &Columns) -> io::Result<()> {
+        self.table_writer.write_header(&columns.names())
+    }
+
+    fn member(&mut self, membership: &Membership) -> io::Result<()> {
+        self.table_writer.write_row(&membership.fields())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (self.finish_fn)(self.table_writer.get_mut()?)
+    }
+}
+
+/// An owned `Membership`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Member {
+    pub representative_id: String,
+    pub id: String,
+    pub rotation_opt: Option<Rotation>,
+    pub key_mates_opt: Option<Vec<usize>>,
+}
+
+/// Collects membership in memory, in the order it was received.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    columns: Columns,
+    members: Vec<Member>,
+}
+
+impl MemorySink {
+    pub fn columns(&self) -> &Columns {
+        &self.columns
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+}
+
+impl MembershipSink for MemorySink {
+    fn begin(&mut self, columns: &Columns) -> io::Result<()> {
+        self.columns = *columns;
+        Ok(())
+    }
+
+    fn member(&mut self, membership: &Membership) -> io::Result<()> {
+        self.members.push(Member {
+            representative_id: membership.representative_id.to_owned(),
+            id: membership.id.to_owned(),
+            rotation_opt: membership.rotation_opt,
+            key_mates_opt: membership.key_mates_opt.map(|key_mates| key_mates.to_vec()),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str;
+
+    #[test]
+    fn test_table_sink() {
+        let columns = Columns {
+            rotation: true,
+            key_mates: false,
+        };
+        let membership = Membership {
+            representative_id: "id_a",
+            id: "id_b",
+            rotation_opt: Some(Rotation {
+                offset: 3,
+                reverse: true,
+                length: 8,
+            }),
+            key_mates_opt: None,
+        };
+        let mut output = Vec::new();
+        {
+            let mut sink = TableSink::new(&mut output, TableFormat::JsonLines);
+            sink.begin(&columns).expect("don't break");
+            sink.member(&membership).expect("don't break");
+            sink.finish().expect("don't break");
+        }
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "{\"representative read id\":\"id_a\",\"read id\":\"id_b\",\"rotation offset\":3,\
+             \"strand\":\"-\"}\n"
+        );
+
+        let mut memory_sink = MemorySink::default();
+        {
+            let mut sink: Box<dyn MembershipSink + '_> = Box::new(&mut memory_sink);
+            sink.begin(&columns).expect("don't break");
+            sink.member(&membership).expect("don't break");
+        }
+        assert_eq!(memory_sink.columns(), &columns);
+        assert_eq!(memory_sink.members()[0].id, "id_b");
+    }
+}
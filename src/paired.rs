@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::{Error, ErrorKind};
 
 use super::fastx;
+use super::format;
 
 pub struct PairedRecord<T: fastx::Record> {
     r1: T,
@@ -29,9 +31,9 @@ impl<T: fastx::Record> PairedRecord<T> {
     }
 }
 
-impl<T: fastx::Record> Into<(T, T)> for PairedRecord<T> {
-    fn into(self) -> (T, T) {
-        (self.r1, self.r2)
+impl<T: fastx::Record> From<PairedRecord<T>> for (T, T) {
+    fn from(record: PairedRecord<T>) -> Self {
+        (record.r1, record.r2)
     }
 }
 
@@ -40,7 +42,7 @@ impl<T: fastx::Record> TryFrom<(T, T)> for PairedRecord<T> {
 
     fn try_from((r1, r2): (T, T)) -> Result<Self, Self::Error> {
         if r1.id() == r2.id() {
-            Ok(PairedRecord { r1: r1, r2: r2 })
+            Ok(PairedRecord { r1, r2 })
         } else {
             let message = format!(
                 "read pair had different read IDs: ({}, {})",
@@ -55,15 +57,25 @@ impl<T: fastx::Record> TryFrom<(T, T)> for PairedRecord<T> {
 pub struct PairedRecords<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> {
     records_r1: R,
     records_r2: R,
+    names: [String; 2],
+    count: u64,
 }
 
 impl<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> PairedRecords<T, R> {
     pub fn new(records_r1: R, records_r2: R) -> Self {
         PairedRecords {
-            records_r1: records_r1,
-            records_r2: records_r2,
+            records_r1,
+            records_r2,
+            names: ["r1".to_owned(), "r2".to_owned()],
+            count: 0,
         }
     }
+
+    /// Names the inputs in errors, usually by their paths.
+    pub fn with_names(mut self, name_r1: &str, name_r2: &str) -> Self {
+        self.names = [name_r1.to_owned(), name_r2.to_owned()];
+        self
+    }
 }
 
 impl<A: fastx::Record, T: Iterator<Item = Result<A, std::io::Error>>> Iterator
@@ -72,25 +84,301 @@ impl<A: fastx::Record, T: Iterator<Item = Result<A, std::io::Error>>> Iterator
     type Item = Result<PairedRecord<A>, Error>;
 
     fn next(&mut self) -> Option<Result<PairedRecord<A>, Error>> {
+        self.count += 1;
+        let [name_r1, name_r2] = &self.names;
         match (self.records_r1.next(), self.records_r2.next()) {
             (Some(Ok(r1_record)), Some(Ok(r2_record))) => {
-                Some(PairedRecord::try_from((r1_record, r2_record)))
+                let location = format!("record {} of {} and {}", self.count, name_r1, name_r2);
+                Some(
+                    PairedRecord::try_from((r1_record, r2_record))
+                        .map_err(|err| format::locate_error(err, &location)),
+                )
             }
             (None, None) => None,
-            (Some(_), None) => Some(Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "reached the end of r2 before r1",
-            ))),
-            (None, Some(_)) => Some(Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "reached the end of r1 before r2",
-            ))),
+            (Some(_), None) => {
+                let message = format!(
+                    "reached the end of {} after {} records, before {}",
+                    name_r2,
+                    self.count - 1,
+                    name_r1
+                );
+                Some(Err(Error::new(ErrorKind::UnexpectedEof, message)))
+            }
+            (None, Some(_)) => {
+                let message = format!(
+                    "reached the end of {} after {} records, before {}",
+                    name_r1,
+                    self.count - 1,
+                    name_r2
+                );
+                Some(Err(Error::new(ErrorKind::UnexpectedEof, message)))
+            }
+            (Some(Err(err)), _) => Some(Err(err)),
+            (_, Some(Err(err))) => Some(Err(err)),
+        }
+    }
+}
+
+/// Builds pairs from consecutive records of a single interleaved input.
+pub struct InterleavedRecords<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> {
+    records: R,
+    name: String,
+    count: u64,
+}
+
+impl<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> InterleavedRecords<T, R> {
+    pub fn new(records: R) -> Self {
+        InterleavedRecords {
+            records,
+            name: "interleaved input".to_owned(),
+            count: 0,
+        }
+    }
+
+    /// Names the input in errors, usually by its path.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+}
+
+impl<A: fastx::Record, T: Iterator<Item = Result<A, std::io::Error>>> Iterator
+    for InterleavedRecords<A, T>
+{
+    type Item = Result<PairedRecord<A>, Error>;
+
+    fn next(&mut self) -> Option<Result<PairedRecord<A>, Error>> {
+        self.count += 2;
+        match (self.records.next(), self.records.next()) {
+            (Some(Ok(r1_record)), Some(Ok(r2_record))) => {
+                let location = format!(
+                    "records {} and {} of {}",
+                    self.count - 1,
+                    self.count,
+                    self.name
+                );
+                Some(
+                    PairedRecord::try_from((r1_record, r2_record))
+                        .map_err(|err| format::locate_error(err, &location)),
+                )
+            }
+            (None, _) => None,
+            (Some(Ok(r1_record)), None) => {
+                let message = format!(
+                    "reached the end of {} after {} records, before r2 of {}",
+                    self.name,
+                    self.count - 1,
+                    r1_record.id()
+                );
+                Some(Err(Error::new(ErrorKind::UnexpectedEof, message)))
+            }
             (Some(Err(err)), _) => Some(Err(err)),
             (_, Some(Err(err))) => Some(Err(err)),
         }
     }
 }
 
+/// Writes the mates of each pair to separate r1 and r2 writers, or interleaved to one writer.
+pub struct PairedWriter<S> {
+    writer_r1: S,
+    writer_r2_opt: Option<S>,
+}
+
+impl<S> PairedWriter<S> {
+    pub fn new(writer_r1: S, writer_r2_opt: Option<S>) -> Self {
+        PairedWriter {
+            writer_r1,
+            writer_r2_opt,
+        }
+    }
+
+    /// Takes one writer for interleaved output or two for separate outputs.
+    pub fn from_writers(writers: Vec<S>) -> Self {
+        let mut writers = writers.into_iter();
+        let writer_r1 = writers
+            .next()
+            .expect("paired writer needs at least one writer");
+        PairedWriter::new(writer_r1, writers.next())
+    }
+
+    pub fn write_r1<T: fastx::Record>(&mut self, record: &T) -> Result<(), Error>
+    where
+        S: fastx::Writer<T>,
+    {
+        self.writer_r1.write_mate(record, 0, 2)
+    }
+
+    pub fn write_r2<T: fastx::Record>(&mut self, record: &T) -> Result<(), Error>
+    where
+        S: fastx::Writer<T>,
+    {
+        self.writer_r2_opt
+            .as_mut()
+            .unwrap_or(&mut self.writer_r1)
+            .write_mate(record, 1, 2)
+    }
+
+    pub fn write_pair<T: fastx::Record>(&mut self, record: &PairedRecord<T>) -> Result<(), Error>
+    where
+        S: fastx::Writer<T>,
+    {
+        self.write_r1(record.r1())?;
+        self.write_r2(record.r2())
+    }
+
+    pub fn finish<T: fastx::Record>(&mut self) -> Result<(), Error>
+    where
+        S: fastx::Writer<T>,
+    {
+        self.writer_r1.finish()?;
+        if let Some(writer_r2) = self.writer_r2_opt.as_mut() {
+            writer_r2.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Each record comes with its index in its input, r1's for pairs.
+pub enum ResyncedRecord<T: fastx::Record> {
+    Paired(PairedRecord<T>, usize),
+    OrphanR1(T, usize),
+    OrphanR2(T, usize),
+}
+
+/// Pairs mates by read ID instead of by position, buffering reads until their mate shows up.
+/// Reads whose mate never appears are returned as orphans, in input order, once both inputs
+/// are exhausted.
+pub struct ResyncedRecords<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> {
+    records_r1: R,
+    records_r2: R,
+    // reads by ID with the order they were read in and their index in their input
+    pending_r1: HashMap<String, (u64, usize, T)>,
+    pending_r2: HashMap<String, (u64, usize, T)>,
+    read_count: u64,
+    names: [String; 2],
+    // records read from each input
+    counts: [u64; 2],
+    read_r1_next: bool,
+    orphans_opt: Option<std::vec::IntoIter<ResyncedRecord<T>>>,
+}
+
+impl<T: fastx::Record, R: Iterator<Item = Result<T, std::io::Error>>> ResyncedRecords<T, R> {
+    pub fn new(records_r1: R, records_r2: R) -> Self {
+        ResyncedRecords {
+            records_r1,
+            records_r2,
+            pending_r1: HashMap::new(),
+            pending_r2: HashMap::new(),
+            read_count: 0,
+            names: ["r1".to_owned(), "r2".to_owned()],
+            counts: [0, 0],
+            read_r1_next: true,
+            orphans_opt: None,
+        }
+    }
+
+    /// Names the inputs in errors, usually by their paths.
+    pub fn with_names(mut self, name_r1: &str, name_r2: &str) -> Self {
+        self.names = [name_r1.to_owned(), name_r2.to_owned()];
+        self
+    }
+
+    fn orphans(&mut self) -> std::vec::IntoIter<ResyncedRecord<T>> {
+        let mut orphans: Vec<(u64, ResyncedRecord<T>)> = self
+            .pending_r1
+            .drain()
+            .map(|(_, (n, index, record))| (n, ResyncedRecord::OrphanR1(record, index)))
+            .chain(
+                self.pending_r2
+                    .drain()
+                    .map(|(_, (n, index, record))| (n, ResyncedRecord::OrphanR2(record, index))),
+            )
+            .collect();
+        orphans.sort_by_key(|(n, _)| *n);
+        orphans
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn match_mate(&mut self, record: T, is_r1: bool) -> Option<Result<ResyncedRecord<T>, Error>> {
+        let input = if is_r1 { 0 } else { 1 };
+        let index = self.counts[input] as usize;
+        self.counts[input] += 1;
+        let (pending, mate_pending) = if is_r1 {
+            (&mut self.pending_r1, &mut self.pending_r2)
+        } else {
+            (&mut self.pending_r2, &mut self.pending_r1)
+        };
+
+        if let Some((_, mate_index, mate)) = mate_pending.remove(record.id()) {
+            let (pair, r1_index) = if is_r1 {
+                ((record, mate), index)
+            } else {
+                ((mate, record), mate_index)
+            };
+            return Some(
+                PairedRecord::try_from(pair).map(|pair| ResyncedRecord::Paired(pair, r1_index)),
+            );
+        }
+
+        if pending.contains_key(record.id()) {
+            let message = format!(
+                "duplicate read ID {} at record {} of {}",
+                record.id(),
+                self.counts[input],
+                self.names[input]
+            );
+            return Some(Err(Error::new(ErrorKind::InvalidData, message)));
+        }
+        pending.insert(record.id().to_owned(), (self.read_count, index, record));
+        self.read_count += 1;
+        None
+    }
+}
+
+impl<A: fastx::Record, T: Iterator<Item = Result<A, std::io::Error>>> Iterator
+    for ResyncedRecords<A, T>
+{
+    type Item = Result<ResyncedRecord<A>, Error>;
+
+    fn next(&mut self) -> Option<Result<ResyncedRecord<A>, Error>> {
+        loop {
+            if let Some(orphans) = self.orphans_opt.as_mut() {
+                return orphans.next().map(Ok);
+            }
+
+            // alternate between the inputs so the buffers stay small while they are in sync
+            let mut is_r1 = self.read_r1_next;
+            self.read_r1_next = !is_r1;
+            let mut result = if is_r1 {
+                self.records_r1.next()
+            } else {
+                self.records_r2.next()
+            };
+            if result.is_none() {
+                is_r1 = !is_r1;
+                result = if is_r1 {
+                    self.records_r1.next()
+                } else {
+                    self.records_r2.next()
+                };
+            }
+
+            match result {
+                Some(Ok(record)) => {
+                    if let Some(resynced) = self.match_mate(record, is_r1) {
+                        return Some(resynced);
+                    }
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => self.orphans_opt = Some(self.orphans()),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -115,7 +403,7 @@ mod test {
         );
         assert_eq!(
             error.to_string(),
-            "reached the end of r2 before r1",
+            "reached the end of r2 after 0 records, before r1",
             "should contain correct message"
         );
     }
@@ -139,7 +427,7 @@ mod test {
         );
         assert_eq!(
             error.to_string(),
-            "reached the end of r1 before r2",
+            "reached the end of r1 after 0 records, before r2",
             "should contain correct message"
         );
     }
@@ -164,7 +452,7 @@ mod test {
         );
         assert_eq!(
             error.to_string(),
-            "read pair had different read IDs: (id_a, id_b)",
+            "read pair had different read IDs: (id_a, id_b) at record 1 of r1 and r2",
             "should contain correct message"
         );
     }
@@ -172,9 +460,8 @@ mod test {
     #[test]
     fn test_r1_error() {
         let records_r1 =
-            vec![Err(Error::new(ErrorKind::Other, "I'm broken")) as Result<fasta::Record, Error>]
-                .into_iter();
-        let records_r2 = vec![Err(Error::new(ErrorKind::Other, "I'm also broken"))].into_iter();
+            vec![Err(Error::other("I'm broken")) as Result<fasta::Record, Error>].into_iter();
+        let records_r2 = vec![Err(Error::other("I'm also broken"))].into_iter();
         let mut paired_iterator = PairedRecords::new(records_r1, records_r2);
         let result = paired_iterator.next();
 
@@ -190,7 +477,7 @@ mod test {
     fn test_r2_error() {
         let record_r1 = fasta::Record::with_attrs("id_a", None, &[]);
         let records_r1 = vec![Ok(record_r1)].into_iter();
-        let records_r2 = vec![Err(Error::new(ErrorKind::Other, "I'm broken"))].into_iter();
+        let records_r2 = vec![Err(Error::other("I'm broken"))].into_iter();
         let mut paired_iterator = PairedRecords::new(records_r1, records_r2);
         let result = paired_iterator.next();
 
@@ -201,4 +488,79 @@ mod test {
         assert_eq!(error.kind(), ErrorKind::Other, "should be of kind Other");
         assert_eq!(error.to_string(), "I'm broken");
     }
+
+    #[test]
+    fn test_resync_out_of_order() {
+        let records_r1 = vec![
+            Ok(fasta::Record::with_attrs("id_a", None, &[])),
+            Ok(fasta::Record::with_attrs("id_b", None, &[])),
+            Ok(fasta::Record::with_attrs("id_c", None, &[])),
+        ]
+        .into_iter();
+        let records_r2 = vec![
+            Ok(fasta::Record::with_attrs("id_b", None, &[])),
+            Ok(fasta::Record::with_attrs("id_a", None, &[])),
+            Ok(fasta::Record::with_attrs("id_d", None, &[])),
+        ]
+        .into_iter();
+        let ids: Vec<String> = ResyncedRecords::new(records_r1, records_r2)
+            .map(|result| match result.expect("should not error") {
+                ResyncedRecord::Paired(record, index) => format!("pair {} {}", record.id(), index),
+                ResyncedRecord::OrphanR1(record, index) => format!("r1 {} {}", record.id(), index),
+                ResyncedRecord::OrphanR2(record, index) => format!("r2 {} {}", record.id(), index),
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec!["pair id_b 1", "pair id_a 0", "r1 id_c 2", "r2 id_d 2"]
+        );
+    }
+
+    #[test]
+    fn test_resync_duplicate_id() {
+        let records_r1 = vec![
+            Ok(fasta::Record::with_attrs("id_a", None, &[])),
+            Ok(fasta::Record::with_attrs("id_a", None, &[])),
+        ]
+        .into_iter();
+        let records_r2 = vec![Ok(fasta::Record::with_attrs("id_b", None, &[]))].into_iter();
+        let error = ResyncedRecords::new(records_r1, records_r2)
+            .find_map(|result| result.err())
+            .expect("should return an error");
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "duplicate read ID id_a at record 2 of r1"
+        );
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let records = vec![
+            Ok(fasta::Record::with_attrs("id_a", None, b"AC")),
+            Ok(fasta::Record::with_attrs("id_a", None, b"GT")),
+            Ok(fasta::Record::with_attrs("id_b", None, b"AC")),
+        ]
+        .into_iter();
+        let mut paired_iterator = InterleavedRecords::new(records);
+
+        let record = paired_iterator
+            .next()
+            .expect("should return an element")
+            .expect("should return a pair");
+        assert_eq!(record.r1().seq(), b"AC");
+        assert_eq!(record.r2().seq(), b"GT");
+
+        let error = paired_iterator
+            .next()
+            .expect("should return an element")
+            .err()
+            .expect("should return an error");
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            error.to_string(),
+            "reached the end of interleaved input after 3 records, before r2 of id_b"
+        );
+        assert!(paired_iterator.next().is_none());
+    }
 }
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::io::Error;
+
+use super::fastx::Record as _;
+use super::sam;
+
+/// The unclipped 5' end of an aligned read, so soft-clipping does not hide duplicates.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct End {
+    ref_id: i32,
+    position: i32,
+    reverse: bool,
+}
+
+impl End {
+    /// `None` if the read is unmapped.
+    pub fn of(record: &sam::Record) -> Option<End> {
+        if record.flag() & sam::FLAG_UNMAPPED != 0 || record.ref_id() < 0 {
+            return None;
+        }
+        let is_clip = |op: &u8| *op == b'S' || *op == b'H';
+        let cigar = record.cigar();
+        let reverse = record.flag() & sam::FLAG_REVERSE != 0;
+        let position = if reverse {
+            let reference_length: u32 = cigar
+                .iter()
+                .filter(|(_, op)| b"MDN=X".contains(op))
+                .map(|(length, _)| length)
+                .sum();
+            let clipped: u32 = cigar
+                .iter()
+                .rev()
+                .take_while(|(_, op)| is_clip(op))
+                .map(|(length, _)| length)
+                .sum();
+            record.pos() + reference_length as i32 + clipped as i32 - 1
+        } else {
+            let clipped: u32 = cigar
+                .iter()
+                .take_while(|(_, op)| is_clip(op))
+                .map(|(length, _)| length)
+                .sum();
+            record.pos() - clipped as i32
+        };
+        Some(End {
+            ref_id: record.ref_id(),
+            position,
+            reverse,
+        })
+    }
+}
+
+/// Templates with the same key are duplicates.
+#[derive(Debug, Eq, Hash, PartialEq)]
+pub enum PositionKey {
+    Single {
+        library: String,
+        end: End,
+    },
+    /// The ends are sorted so a pair matches its mirror image. An unmapped mate sorts first.
+    Paired {
+        library: String,
+        ends: (Option<End>, Option<End>),
+    },
+    /// Unmapped templates have no position so they are never duplicates.
+    Unmapped(String),
+}
+
+/// A read, or both mates of a pair, found by read name anywhere in the input.
+pub struct Template {
+    name: String,
+    library: String,
+    paired: bool,
+    r1_opt: Option<End>,
+    r2_opt: Option<End>,
+}
+
+impl Template {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn key(&self) -> PositionKey {
+        match (self.paired, self.r1_opt, self.r2_opt) {
+            (false, Some(end), _) => PositionKey::Single {
+                library: self.library.clone(),
+                end,
+            },
+            (true, r1_opt, r2_opt) if r1_opt.is_some() || r2_opt.is_some() => {
+                let ends = if r1_opt <= r2_opt {
+                    (r1_opt, r2_opt)
+                } else {
+                    (r2_opt, r1_opt)
+                };
+                PositionKey::Paired {
+                    library: self.library.clone(),
+                    ends,
+                }
+            }
+            _ => PositionKey::Unmapped(self.name.clone()),
+        }
+    }
+}
+
+/// Templates in order of their first primary record. Secondary and supplementary alignments are
+/// skipped, they share the status of their primary alignment.
+pub struct Templates {
+    templates: Vec<Template>,
+    indices: HashMap<String, usize>,
+}
+
+impl Templates {
+    pub fn from_records<R: Iterator<Item = Result<sam::Record, Error>>>(
+        records: R,
+        libraries: &HashMap<String, String>,
+    ) -> Result<Self, Error> {
+        let mut templates = Vec::new();
+        let mut indices = HashMap::new();
+        for result in records {
+            let record = result?;
+            if record.flag() & (sam::FLAG_SECONDARY | sam::FLAG_SUPPLEMENTARY) != 0 {
+                continue;
+            }
+            let index = *indices.entry(record.id().to_owned()).or_insert_with(|| {
+                let library = record
+                    .string_tag(b"RG")
+                    .and_then(|read_group| libraries.get(&read_group).cloned())
+                    .unwrap_or_default();
+                templates.push(Template {
+                    name: record.id().to_owned(),
+                    library,
+                    paired: record.flag() & sam::FLAG_PAIRED != 0,
+                    r1_opt: None,
+                    r2_opt: None,
+                });
+                templates.len() - 1
+            });
+            let template = &mut templates[index];
+            if record.flag() & sam::FLAG_LAST != 0 {
+                template.r2_opt = End::of(&record);
+            } else {
+                template.r1_opt = End::of(&record);
+            }
+        }
+        Ok(Templates { templates, indices })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Template> {
+        self.templates.iter()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn read_templates(sam: &str) -> Templates {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("input.sam");
+        std::fs::write(&path, sam).unwrap();
+        let reader = sam::Reader::from_file(&path).expect("don't break");
+        let libraries = reader.header().libraries();
+        Templates::from_records(reader.records(), &libraries).expect("don't break")
+    }
+
+    #[test]
+    fn test_unclipped_ends() {
+        let templates = read_templates(
+            "@SQ\tSN:chr1\tLN:1000\n\
+            @RG\tID:rg1\tLB:lib1\n\
+            a\t0\tchr1\t101\t60\t3S7M\t*\t0\t0\tACGTACGTAC\t*\tRG:Z:rg1\n\
+            b\t0\tchr1\t98\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\tRG:Z:rg1\n\
+            c\t16\tchr1\t101\t60\t5M2D3M2S\t*\t0\t0\tACGTACGTAC\t*\n\
+            d\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\t*\n",
+        );
+        let keys: Vec<PositionKey> = templates.iter().map(|template| template.key()).collect();
+        // soft-clipping moves the alignment start but not the unclipped 5' end
+        assert_eq!(keys[0], keys[1]);
+        assert_eq!(
+            keys[2],
+            PositionKey::Single {
+                library: String::new(),
+                end: End {
+                    ref_id: 0,
+                    position: 100 + 10 + 2 - 1,
+                    reverse: true
+                }
+            }
+        );
+        assert_eq!(keys[3], PositionKey::Unmapped("d".to_owned()));
+    }
+
+    #[test]
+    fn test_paired_ends() {
+        let templates = read_templates(
+            "@SQ\tSN:chr1\tLN:1000\n\
+            a\t99\tchr1\t101\t60\t10M\t=\t201\t110\tACGTACGTAC\t*\n\
+            b\t163\tchr1\t101\t60\t10M\t=\t201\t110\tACGTACGTAC\t*\n\
+            a\t147\tchr1\t201\t60\t10M\t=\t101\t-110\tACGTACGTAC\t*\n\
+            a\t2195\tchr1\t501\t60\t5M\t=\t101\t0\tACGTA\t*\n\
+            b\t83\tchr1\t201\t60\t10M\t=\t101\t-110\tACGTACGTAC\t*\n",
+        );
+        assert_eq!(templates.index_of("b"), Some(1));
+        let keys: Vec<PositionKey> = templates.iter().map(|template| template.key()).collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], keys[1]);
+    }
+}
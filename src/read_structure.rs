@@ -0,0 +1,152 @@
+use std::cmp;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SegmentType {
+    Barcode,
+    Umi,
+    Template,
+    Skip,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    segment_type: SegmentType,
+    length_opt: Option<usize>,
+}
+
+/// Describes where the cell barcode (`B`), UMI (`U`), template (`T`) and skipped bases (`S`) sit
+/// in a read, e.g. `16B12U` or `T`. A segment without a length takes the rest of the read, so it
+/// may only come last.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReadStructure {
+    segments: Vec<Segment>,
+}
+
+#[derive(Default)]
+pub struct ReadSegments {
+    pub barcode: Vec<u8>,
+    pub umi: Vec<u8>,
+    pub template: Vec<u8>,
+}
+
+impl ReadStructure {
+    /// Segments past the end of a short read are truncated rather than treated as an error.
+    pub fn extract(&self, seq: &[u8]) -> ReadSegments {
+        let mut segments = ReadSegments::default();
+        let mut start = 0;
+        for segment in self.segments.iter() {
+            let end = segment
+                .length_opt
+                .map(|length| cmp::min(start + length, seq.len()))
+                .unwrap_or_else(|| seq.len());
+            let bases = &seq[start..end];
+            match segment.segment_type {
+                SegmentType::Barcode => segments.barcode.extend_from_slice(bases),
+                SegmentType::Umi => segments.umi.extend_from_slice(bases),
+                SegmentType::Template => segments.template.extend_from_slice(bases),
+                SegmentType::Skip => (),
+            }
+            start = end;
+        }
+        segments
+    }
+}
+
+impl FromStr for ReadStructure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut digits = String::new();
+        for c in s.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            let segment_type = match c.to_ascii_uppercase() {
+                'B' => SegmentType::Barcode,
+                'U' => SegmentType::Umi,
+                'T' => SegmentType::Template,
+                'S' => SegmentType::Skip,
+                _ => {
+                    return Err(format!(
+                        "invalid read structure {}: unknown segment type {}",
+                        s, c
+                    ))
+                }
+            };
+            if segments
+                .last()
+                .map(|segment: &Segment| segment.length_opt.is_none())
+                .unwrap_or(false)
+            {
+                return Err(format!(
+                    "invalid read structure {}: only the last segment may omit its length",
+                    s
+                ));
+            }
+            let length_opt = if digits.is_empty() {
+                None
+            } else {
+                // only ascii digits so the only possible failure is overflow
+                Some(
+                    digits
+                        .parse::<usize>()
+                        .map_err(|err| format!("invalid read structure {}: {}", s, err))?,
+                )
+            };
+            digits.clear();
+            segments.push(Segment {
+                segment_type,
+                length_opt,
+            });
+        }
+        if !digits.is_empty() || segments.is_empty() {
+            return Err(format!(
+                "invalid read structure {}: expected a segment type after each length",
+                s
+            ));
+        }
+        Ok(ReadStructure { segments })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let read_structure = ReadStructure::from_str("4B3U2S").unwrap();
+        let segments = read_structure.extract(b"AAAACCCGGTT");
+        assert_eq!(segments.barcode, b"AAAA");
+        assert_eq!(segments.umi, b"CCC");
+        assert!(segments.template.is_empty());
+
+        let read_structure = ReadStructure::from_str("2S4T").unwrap();
+        assert_eq!(read_structure.extract(b"AAC").template, b"C");
+
+        let read_structure = ReadStructure::from_str("4B1S4B").unwrap();
+        assert_eq!(read_structure.extract(b"AAAACGGGGT").barcode, b"AAAAGGGG");
+
+        let read_structure = ReadStructure::from_str("T").unwrap();
+        assert_eq!(read_structure.extract(b"GG").template, b"GG");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            ReadStructure::from_str("16X").unwrap_err(),
+            "invalid read structure 16X: unknown segment type X"
+        );
+        assert_eq!(
+            ReadStructure::from_str("T12U").unwrap_err(),
+            "invalid read structure T12U: only the last segment may omit its length"
+        );
+        assert_eq!(
+            ReadStructure::from_str("16B12").unwrap_err(),
+            "invalid read structure 16B12: expected a segment type after each length"
+        );
+    }
+}
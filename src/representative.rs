@@ -0,0 +1,146 @@
+use super::fastx;
+
+// FASTQ qualities are phred scores offset by this
+const QUALITY_OFFSET: u8 = b'!';
+
+/// The total length and base quality of a record's members. Records without qualities, such as
+/// FASTA, have no quality.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Measure {
+    pub length: usize,
+    pub quality_opt: Option<u64>,
+}
+
+impl Measure {
+    pub fn of(members: &[&dyn fastx::Record]) -> Self {
+        let length = members.iter().map(|member| member.seq().len()).sum();
+        let quality_opt = members
+            .iter()
+            .map(|member| {
+                member.qual().map(|qual| {
+                    qual.iter()
+                        .map(|score| u64::from(score.saturating_sub(QUALITY_OFFSET)))
+                        .sum::<u64>()
+                })
+            })
+            .sum();
+        Measure {
+            length,
+            quality_opt,
+        }
+    }
+
+    pub fn mean_quality(&self) -> Option<f64> {
+        match self.quality_opt {
+            Some(quality) if self.length > 0 => Some(quality as f64 / self.length as f64),
+            _ => None,
+        }
+    }
+}
+
+/// A member of a cluster as a representative policy sees it. `index` is its position among every
+/// inserted record.
+pub struct Candidate<'a> {
+    pub id: &'a str,
+    pub index: u64,
+    pub measure: Measure,
+}
+
+/// Chooses the representative of each cluster as its members arrive, starting from the first.
+pub trait RepresentativePolicy {
+    /// Whether `candidate`, a new member of a cluster, replaces `current` as its representative.
+    fn replaces(&self, candidate: &Candidate, current: &Candidate) -> bool;
+}
+
+/// Keeps the first member, as without a policy.
+pub struct FirstSeen;
+
+impl RepresentativePolicy for FirstSeen {
+    fn replaces(&self, _candidate: &Candidate, _current: &Candidate) -> bool {
+        false
+    }
+}
+
+/// Keeps the member with the highest mean base quality, then the first. Members without
+/// qualities never replace one with them.
+pub struct HighestQuality;
+
+impl RepresentativePolicy for HighestQuality {
+    fn replaces(&self, candidate: &Candidate, current: &Candidate) -> bool {
+        match (
+            candidate.measure.mean_quality(),
+            current.measure.mean_quality(),
+        ) {
+            (Some(candidate_quality), Some(current_quality)) => candidate_quality > current_quality,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Keeps the longest member, then the first.
+pub struct Longest;
+
+impl RepresentativePolicy for Longest {
+    fn replaces(&self, candidate: &Candidate, current: &Candidate) -> bool {
+        candidate.measure.length > current.measure.length
+    }
+}
+
+/// Keeps the member with the lexicographically smallest read ID.
+pub struct SmallestId;
+
+impl RepresentativePolicy for SmallestId {
+    fn replaces(&self, candidate: &Candidate, current: &Candidate) -> bool {
+        candidate.id < current.id
+    }
+}
+
+/// Parses the name of a built-in policy.
+pub fn by_name(name: &str) -> Result<Box<dyn RepresentativePolicy>, String> {
+    match name {
+        "first" => Ok(Box::new(FirstSeen)),
+        "quality" => Ok(Box::new(HighestQuality)),
+        "longest" => Ok(Box::new(Longest)),
+        "smallest-id" => Ok(Box::new(SmallestId)),
+        _ => Err(format!(
+            "representative policy {} is not one of first, quality, longest, smallest-id",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bio::io::fastq;
+
+    #[test]
+    fn test_policies() {
+        let r1 = fastq::Record::with_attrs("id_b", None, b"ACGT", b"IIII");
+        let r2 = fastq::Record::with_attrs("id_b", None, b"ACG", b"!!!");
+        let measure = Measure::of(&[&r1, &r2]);
+        assert_eq!(
+            measure,
+            Measure {
+                length: 7,
+                quality_opt: Some(160)
+            }
+        );
+        let current = Candidate {
+            id: "id_b",
+            index: 0,
+            measure,
+        };
+        let candidate = Candidate {
+            id: "id_a",
+            index: 1,
+            measure: Measure::of(&[&r1]),
+        };
+        assert!(!FirstSeen.replaces(&candidate, &current));
+        assert!(HighestQuality.replaces(&candidate, &current));
+        assert!(!Longest.replaces(&candidate, &current));
+        assert!(SmallestId.replaces(&candidate, &current));
+        assert!(by_name("best").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use bio::alphabets::dna;
+
+/// Which strands of a circular sequence are compared.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Strands {
+    Forward,
+    /// A sequence and its reverse complement are the same molecule.
+    Both,
+}
+
+/// How a circular sequence lines up with another: reverse complemented if `reverse`, then
+/// started at `offset`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rotation {
+    pub offset: usize,
+    pub reverse: bool,
+    pub length: usize,
+}
+
+impl Rotation {
+    /// How to turn a sequence into `representative` given how each turns into their shared
+    /// canonical rotation.
+    pub fn relative_to(&self, representative: &Rotation) -> Rotation {
+        let length = std::cmp::max(self.length, 1);
+        let (offset, reverse) = if representative.reverse {
+            // reverse complementing a rotation reverses its offset
+            (
+                (representative.offset + length - self.offset) % length,
+                !self.reverse,
+            )
+        } else {
+            (
+                (self.offset + length - representative.offset) % length,
+                self.reverse,
+            )
+        };
+        Rotation {
+            offset,
+            reverse,
+            length: self.length,
+        }
+    }
+}
+
+/// Start of the lexicographically minimal rotation, the smallest if it repeats.
+pub fn minimal_rotation(seq: &[u8]) -> usize {
+    let n = seq.len();
+    let (mut i, mut j, mut k) = (0, 1, 0);
+    while i < n && j < n && k < n {
+        let (a, b) = (seq[(i + k) % n], seq[(j + k) % n]);
+        if a == b {
+            k += 1;
+            continue;
+        }
+        if a > b {
+            i += k + 1;
+        } else {
+            j += k + 1;
+        }
+        if i == j {
+            j += 1;
+        }
+        k = 0;
+    }
+    std::cmp::min(i, j)
+}
+
+fn rotate(seq: &[u8], offset: usize) -> Vec<u8> {
+    let mut rotated = seq[offset..].to_vec();
+    rotated.extend_from_slice(&seq[..offset]);
+    rotated
+}
+
+/// The minimal rotation of a circular sequence, or of either strand, and how the sequence turns
+/// into it.
+pub fn canonical(seq: &[u8], strands: Strands) -> (Vec<u8>, Rotation) {
+    let offset = minimal_rotation(seq);
+    let forward = (
+        rotate(seq, offset),
+        Rotation {
+            offset,
+            reverse: false,
+            length: seq.len(),
+        },
+    );
+    if strands == Strands::Forward {
+        return forward;
+    }
+    let revcomp = dna::revcomp(seq);
+    let offset = minimal_rotation(&revcomp);
+    let reverse = rotate(&revcomp, offset);
+    if reverse < forward.0 {
+        let rotation = Rotation {
+            offset,
+            reverse: true,
+            length: seq.len(),
+        };
+        (reverse, rotation)
+    } else {
+        forward
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(seq: &[u8], rotation: &Rotation) -> Vec<u8> {
+        let oriented = if rotation.reverse {
+            dna::revcomp(seq)
+        } else {
+            seq.to_vec()
+        };
+        rotate(&oriented, rotation.offset)
+    }
+
+    #[test]
+    fn test_minimal_rotation() {
+        assert_eq!(minimal_rotation(b"CAB"), 1);
+        assert_eq!(minimal_rotation(b"BABA"), 1);
+        assert_eq!(minimal_rotation(b""), 0);
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let representative = b"GATTACAGG";
+        let (canonical_representative, representative_rotation) =
+            canonical(representative, Strands::Both);
+        for member in [
+            representative.to_vec(),
+            rotate(representative, 4),
+            dna::revcomp(rotate(representative, 2)),
+        ]
+        .iter()
+        {
+            let (canonical_member, member_rotation) = canonical(member, Strands::Both);
+            assert_eq!(canonical_member, canonical_representative);
+            let rotation = member_rotation.relative_to(&representative_rotation);
+            assert_eq!(apply(member, &rotation), representative.to_vec());
+        }
+    }
+}
@@ -0,0 +1,1483 @@
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use super::fastx;
+use super::format;
+use super::paired::PairedRecord;
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_REVERSE: u16 = 0x10;
+pub const FLAG_FIRST: u16 = 0x40;
+pub const FLAG_LAST: u16 = 0x80;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_DUPLICATE: u16 = 0x400;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+// BGZF blocks hold at most 64KiB, leave room for incompressible data
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn invalid_data<S: Into<String>>(message: S) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Sam,
+    Bam,
+}
+
+/// Only the reference dictionary is parsed, the rest of the header is carried through as text.
+#[derive(Clone, Debug, Default)]
+pub struct Header {
+    text: String,
+    references: Vec<(String, u32)>,
+}
+
+impl Header {
+    fn from_text(text: String) -> Self {
+        let references = text
+            .lines()
+            .filter(|line| line.starts_with("@SQ\t"))
+            .filter_map(|line| {
+                let field = |tag: &str| {
+                    line.split('\t')
+                        .find(|field| field.starts_with(tag))
+                        .map(|field| field[tag.len()..].to_owned())
+                };
+                let name = field("SN:")?;
+                let length = field("LN:").and_then(|length| length.parse().ok())?;
+                Some((name, length))
+            })
+            .collect();
+        Header { text, references }
+    }
+
+    fn reference_id(&self, name: &str) -> Result<i32, Error> {
+        if name == "*" {
+            return Ok(-1);
+        }
+        self.references
+            .iter()
+            .position(|(reference, _)| reference == name)
+            .map(|id| id as i32)
+            .ok_or_else(|| invalid_data(format!("reference {} is not in the header", name)))
+    }
+
+    fn reference_name(&self, id: i32) -> &str {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| self.references.get(id))
+            .map(|(name, _)| name.as_str())
+            .unwrap_or("*")
+    }
+
+    /// Library (`LB`) of each read group (`ID`) in the `@RG` lines.
+    pub fn libraries(&self) -> HashMap<String, String> {
+        self.text
+            .lines()
+            .filter(|line| line.starts_with("@RG\t"))
+            .filter_map(|line| {
+                let field = |tag: &str| {
+                    line.split('\t')
+                        .find(|field| field.starts_with(tag))
+                        .map(|field| field[tag.len()..].to_owned())
+                };
+                Some((field("ID:")?, field("LB:")?))
+            })
+            .collect()
+    }
+}
+
+/// Optional fields are kept in the encoding of the file they came from so they are written back
+/// unchanged, and converted when written in the other encoding.
+#[derive(Clone, Debug)]
+enum Aux {
+    Sam(Vec<String>),
+    Bam(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    name: String,
+    flag: u16,
+    ref_id: i32,
+    pos: i32,
+    mapq: u8,
+    cigar: Vec<(u32, u8)>,
+    next_ref_id: i32,
+    next_pos: i32,
+    tlen: i32,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    aux: Aux,
+}
+
+impl Record {
+    pub fn flag(&self) -> u16 {
+        self.flag
+    }
+
+    pub fn set_flag(&mut self, flag: u16) {
+        self.flag = flag;
+    }
+
+    pub fn ref_id(&self) -> i32 {
+        self.ref_id
+    }
+
+    /// 0-based leftmost mapping position.
+    pub fn pos(&self) -> i32 {
+        self.pos
+    }
+
+    pub fn cigar(&self) -> &[(u32, u8)] {
+        &self.cigar
+    }
+
+    /// The BAM index bin of the reference span, from the SAM specification's `reg2bin`. Reads
+    /// without a span are given the one base at their position.
+    fn bin(&self) -> u16 {
+        let begin = i64::from(self.pos);
+        let span: i64 = self
+            .cigar
+            .iter()
+            .filter(|(_, op)| b"MDN=X".contains(op))
+            .map(|(length, _)| i64::from(*length))
+            .sum();
+        let end = begin + std::cmp::max(span, 1) - 1;
+        for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)].iter() {
+            if begin >> shift == end >> shift {
+                return (offset + (begin >> shift)) as u16;
+            }
+        }
+        0
+    }
+
+    /// The value of a string (`Z`) optional field.
+    pub fn string_tag(&self, tag: &[u8; 2]) -> Option<String> {
+        match &self.aux {
+            Aux::Sam(fields) => fields.iter().find_map(|field| {
+                let bytes = field.as_bytes();
+                if bytes.len() >= 5 && &bytes[..2] == tag && &bytes[2..5] == b":Z:" {
+                    Some(field[5..].to_owned())
+                } else {
+                    None
+                }
+            }),
+            Aux::Bam(aux) => {
+                let mut i = 0;
+                while i + 3 <= aux.len() {
+                    let value_type = aux[i + 2];
+                    let value = &aux[i + 3..];
+                    let value_length = bam_aux_value_length(value_type, value)?;
+                    if &aux[i..i + 2] == tag && value_type == b'Z' {
+                        let value = &value[..value_length - 1];
+                        return Some(String::from_utf8_lossy(value).into_owned());
+                    }
+                    i += 3 + value_length;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn push_string_tag(&mut self, tag: &[u8; 2], value: &str) {
+        match &mut self.aux {
+            Aux::Sam(fields) => {
+                fields.push(format!("{}:Z:{}", String::from_utf8_lossy(tag), value))
+            }
+            Aux::Bam(aux) => {
+                aux.extend_from_slice(tag);
+                aux.push(b'Z');
+                aux.extend_from_slice(value.as_bytes());
+                aux.push(0);
+            }
+        }
+    }
+}
+
+/// Length in bytes of a BAM optional field value of the given type, `None` if it is malformed.
+fn bam_aux_value_length(value_type: u8, value: &[u8]) -> Option<usize> {
+    let length = match value_type {
+        b'A' | b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        b'i' | b'I' | b'f' => 4,
+        b'Z' | b'H' => value.iter().position(|b| *b == 0)? + 1,
+        b'B' => {
+            let element_length = match *value.first()? {
+                b'c' | b'C' => 1,
+                b's' | b'S' => 2,
+                b'i' | b'I' | b'f' => 4,
+                _ => return None,
+            };
+            let count = u32::from_le_bytes(<[u8; 4]>::try_from(value.get(1..5)?).ok()?);
+            5 + element_length * count as usize
+        }
+        _ => return None,
+    };
+    if length <= value.len() {
+        Some(length)
+    } else {
+        None
+    }
+}
+
+/// Encodes SAM optional fields as BAM, integers taking the smallest type that holds them.
+fn sam_aux_to_bam(fields: &[String]) -> Result<Vec<u8>, Error> {
+    let mut aux = Vec::new();
+    for field in fields.iter() {
+        let invalid = || invalid_data(format!("invalid SAM optional field {}", field));
+        let mut parts = field.splitn(3, ':');
+        let (tag, value_type, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(tag), Some(value_type), Some(value)) if tag.len() == 2 => {
+                (tag, value_type, value)
+            }
+            _ => return Err(invalid()),
+        };
+        aux.extend_from_slice(tag.as_bytes());
+        match value_type {
+            "A" if value.len() == 1 => {
+                aux.push(b'A');
+                aux.push(value.as_bytes()[0]);
+            }
+            "i" => {
+                let number = value.parse::<i64>().map_err(|_| invalid())?;
+                if let Ok(number) = u8::try_from(number) {
+                    aux.push(b'C');
+                    aux.push(number);
+                } else if let Ok(number) = i8::try_from(number) {
+                    aux.push(b'c');
+                    aux.extend_from_slice(&number.to_le_bytes());
+                } else if let Ok(number) = u16::try_from(number) {
+                    aux.push(b'S');
+                    aux.extend_from_slice(&number.to_le_bytes());
+                } else if let Ok(number) = i16::try_from(number) {
+                    aux.push(b's');
+                    aux.extend_from_slice(&number.to_le_bytes());
+                } else if let Ok(number) = u32::try_from(number) {
+                    aux.push(b'I');
+                    aux.extend_from_slice(&number.to_le_bytes());
+                } else if let Ok(number) = i32::try_from(number) {
+                    aux.push(b'i');
+                    aux.extend_from_slice(&number.to_le_bytes());
+                } else {
+                    return Err(invalid());
+                }
+            }
+            "f" => {
+                let number = value.parse::<f32>().map_err(|_| invalid())?;
+                aux.push(b'f');
+                aux.extend_from_slice(&number.to_le_bytes());
+            }
+            "Z" | "H" => {
+                aux.push(value_type.as_bytes()[0]);
+                aux.extend_from_slice(value.as_bytes());
+                aux.push(0);
+            }
+            "B" => {
+                let mut elements = value.split(',');
+                let element_type = match elements.next() {
+                    Some(element_type) if element_type.len() == 1 => element_type.as_bytes()[0],
+                    _ => return Err(invalid()),
+                };
+                let elements: Vec<&str> = elements.collect();
+                aux.push(b'B');
+                aux.push(element_type);
+                aux.extend_from_slice(&(elements.len() as u32).to_le_bytes());
+                for element in elements {
+                    match element_type {
+                        b'c' => aux.extend_from_slice(
+                            &element.parse::<i8>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b'C' => aux.extend_from_slice(
+                            &element.parse::<u8>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b's' => aux.extend_from_slice(
+                            &element.parse::<i16>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b'S' => aux.extend_from_slice(
+                            &element.parse::<u16>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b'i' => aux.extend_from_slice(
+                            &element.parse::<i32>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b'I' => aux.extend_from_slice(
+                            &element.parse::<u32>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        b'f' => aux.extend_from_slice(
+                            &element.parse::<f32>().map_err(|_| invalid())?.to_le_bytes(),
+                        ),
+                        _ => return Err(invalid()),
+                    }
+                }
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(aux)
+}
+
+/// Decodes BAM optional fields as SAM, every integer type becoming `i`.
+fn bam_aux_to_sam(aux: &[u8]) -> Result<Vec<String>, Error> {
+    let invalid = || invalid_data("BAM optional fields are malformed");
+    let mut fields = Vec::new();
+    let mut i = 0;
+    while i < aux.len() {
+        if i + 3 > aux.len() {
+            return Err(invalid());
+        }
+        let tag = String::from_utf8_lossy(&aux[i..i + 2]);
+        let value_type = aux[i + 2];
+        let value = &aux[i + 3..];
+        let value_length = bam_aux_value_length(value_type, value).ok_or_else(invalid)?;
+        let value = &value[..value_length];
+        let number = |bytes: &[u8], element_type: u8| -> String {
+            match element_type {
+                b'c' => (bytes[0] as i8).to_string(),
+                b'C' => bytes[0].to_string(),
+                b's' => i16::from_le_bytes([bytes[0], bytes[1]]).to_string(),
+                b'S' => u16::from_le_bytes([bytes[0], bytes[1]]).to_string(),
+                b'i' => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).to_string(),
+                b'I' => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).to_string(),
+                _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).to_string(),
+            }
+        };
+        let field = match value_type {
+            b'A' => format!("{}:A:{}", tag, value[0] as char),
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+                format!("{}:i:{}", tag, number(value, value_type))
+            }
+            b'f' => format!("{}:f:{}", tag, number(value, value_type)),
+            b'Z' | b'H' => format!(
+                "{}:{}:{}",
+                tag,
+                value_type as char,
+                String::from_utf8_lossy(&value[..value.len() - 1])
+            ),
+            // B, the only other type bam_aux_value_length accepts
+            _ => {
+                let element_type = value[0];
+                let element_length = (value.len() - 5)
+                    / std::cmp::max(
+                        1,
+                        u32::from_le_bytes([value[1], value[2], value[3], value[4]]) as usize,
+                    );
+                let mut field = format!("{}:B:{}", tag, element_type as char);
+                if element_length > 0 {
+                    for element in value[5..].chunks(element_length) {
+                        field.push(',');
+                        field.push_str(&number(element, element_type));
+                    }
+                }
+                field
+            }
+        };
+        fields.push(field);
+        i += 3 + value_length;
+    }
+    Ok(fields)
+}
+
+impl fastx::Record for Record {
+    fn id(&self) -> &str {
+        &self.name
+    }
+
+    fn desc(&self) -> Option<&str> {
+        None
+    }
+
+    fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        if self.qual.is_empty() {
+            None
+        } else {
+            Some(&self.qual)
+        }
+    }
+
+    fn check(&self) -> Result<(), &str> {
+        if self.name.is_empty() {
+            return Err("Expecting read name to be non-empty.");
+        }
+        if !self.qual.is_empty() && self.qual.len() != self.seq.len() {
+            return Err("Unequal length of sequence an qualities.");
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Reader {
+    inner: Box<dyn BufRead>,
+    format: Format,
+    header: Header,
+    // the first SAM record line is read while looking for the end of the header
+    pending_line_opt: Option<String>,
+    tracker: format::Tracker,
+}
+
+impl Reader {
+    /// BAM is told from SAM by its magic number, both may be compressed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let (mut file, tracker) = format::open(path.as_ref())?;
+        if file.fill_buf()?.starts_with(BAM_MAGIC) {
+            let mut inner = file;
+            inner.consume(BAM_MAGIC.len());
+            let header = read_bam_header(&mut inner).map_err(|err| {
+                format::locate_error(
+                    err,
+                    &format!("the BAM header of {}", path.as_ref().display()),
+                )
+            })?;
+            return Ok(Reader {
+                inner,
+                format: Format::Bam,
+                header,
+                pending_line_opt: None,
+                tracker,
+            });
+        }
+
+        let mut text = String::new();
+        let mut pending_line_opt = None;
+        loop {
+            let mut line = String::new();
+            if file.read_line(&mut line)? == 0 {
+                break;
+            }
+            if line.starts_with('@') {
+                text.push_str(&line);
+            } else {
+                pending_line_opt = Some(line);
+                break;
+            }
+        }
+        Ok(Reader {
+            inner: file,
+            format: Format::Sam,
+            header: Header::from_text(text),
+            pending_line_opt,
+            tracker,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Lines of BAM are meaningless so it isn't tracked.
+    pub fn tracker(&self) -> Option<format::Tracker> {
+        match self.format {
+            Format::Sam => Some(self.tracker.clone()),
+            Format::Bam => None,
+        }
+    }
+
+    /// Like `records`, adding where errors happened.
+    pub fn located_records(self, path: &Path) -> format::LocatedRecords<Records> {
+        let tracker_opt = self.tracker();
+        format::LocatedRecords::new(self.records(), path, tracker_opt)
+    }
+
+    pub fn records(self) -> Records {
+        Records { reader: self }
+    }
+
+    fn read_bam_record(&mut self) -> Option<Result<Record, Error>> {
+        let mut block_size = [0; 4];
+        match self.inner.read(&mut block_size[..1]) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(err) => return Some(Err(err)),
+        }
+        if let Err(err) = self.inner.read_exact(&mut block_size[1..]) {
+            return Some(Err(err));
+        }
+        let block_size = match usize::try_from(i32::from_le_bytes(block_size)) {
+            Ok(block_size) => block_size,
+            Err(_) => return Some(Err(invalid_data("BAM record is truncated"))),
+        };
+        // read rather than allocate up front, so a garbage size fails at the end of the file
+        let mut block = Vec::new();
+        match (&mut self.inner)
+            .take(block_size as u64)
+            .read_to_end(&mut block)
+        {
+            Ok(length) if length == block_size => Some(parse_bam_record(&block)),
+            Ok(_) => Some(Err(invalid_data("BAM record is truncated"))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    fn read_sam_record(&mut self) -> Option<Result<Record, Error>> {
+        let line = match self.pending_line_opt.take() {
+            Some(line) => line,
+            None => {
+                let mut line = String::new();
+                match self.inner.read_line(&mut line) {
+                    Ok(0) => return None,
+                    Ok(_) => line,
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+        };
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            return self.read_sam_record();
+        }
+        Some(parse_sam_record(line, &self.header))
+    }
+}
+
+pub struct Records {
+    reader: Reader,
+}
+
+impl Iterator for Records {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Result<Record, Error>> {
+        match self.reader.format {
+            Format::Bam => self.reader.read_bam_record(),
+            Format::Sam => self.reader.read_sam_record(),
+        }
+    }
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> Result<i32, Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+/// Reads a length of the BAM header, which must not be negative.
+fn read_length<R: Read>(reader: &mut R) -> Result<usize, Error> {
+    let length = read_i32(reader)?;
+    usize::try_from(length).map_err(|_| invalid_data(format!("length {} is negative", length)))
+}
+
+/// Reads `length` bytes, without allocating them up front so a garbage length fails at the end of
+/// the input.
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        let message = format!("reached the end of the input reading {} bytes", length);
+        return Err(Error::new(ErrorKind::UnexpectedEof, message));
+    }
+    Ok(bytes)
+}
+
+/// Reads the BAM header after the magic number.
+fn read_bam_header<R: Read>(reader: &mut R) -> Result<Header, Error> {
+    let text_length = read_length(reader)?;
+    let text = read_bytes(reader, text_length)?;
+    // the text may be NUL padded
+    let text = String::from_utf8_lossy(&text)
+        .trim_end_matches('\0')
+        .to_owned();
+    let mut header = Header::from_text(text);
+    let reference_count = read_length(reader)?;
+    header.references.clear();
+    for _ in 0..reference_count {
+        let name_length = read_length(reader)?;
+        let name = read_bytes(reader, name_length)?;
+        let name = String::from_utf8_lossy(&name[..name_length.saturating_sub(1)]);
+        let length = read_i32(reader)? as u32;
+        header.references.push((name.into_owned(), length));
+    }
+    Ok(header)
+}
+
+fn parse_bam_record(block: &[u8]) -> Result<Record, Error> {
+    let truncated = || invalid_data("BAM record is truncated");
+    if block.len() < 32 {
+        return Err(truncated());
+    }
+    let i32_at =
+        |i: usize| i32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+    let u16_at = |i: usize| u16::from_le_bytes([block[i], block[i + 1]]);
+    let name_length = block[8] as usize;
+    let cigar_length = u16_at(12) as usize;
+    let seq_length = usize::try_from(i32_at(16)).map_err(|_| truncated())?;
+
+    let name_start = 32;
+    let cigar_start = name_start + name_length;
+    let seq_start = cigar_start + 4 * cigar_length;
+    let qual_start = seq_start
+        .checked_add(seq_length.div_ceil(2))
+        .ok_or_else(truncated)?;
+    let aux_start = qual_start.checked_add(seq_length).ok_or_else(truncated)?;
+    if aux_start > block.len() || name_length == 0 {
+        return Err(truncated());
+    }
+
+    let name = String::from_utf8_lossy(&block[name_start..cigar_start - 1]).into_owned();
+    let cigar = (0..cigar_length)
+        .map(|i| {
+            let op = i32_at(cigar_start + 4 * i) as u32;
+            (op >> 4, b"MIDNSHP=X"[(op & 0xf) as usize % 9])
+        })
+        .collect();
+    let seq = (0..seq_length)
+        .map(|i| {
+            let byte = block[seq_start + i / 2];
+            let code = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            SEQ_CODES[code as usize]
+        })
+        .collect();
+    let qual = &block[qual_start..aux_start];
+    let qual = if qual.iter().all(|q| *q == 0xff) {
+        Vec::new()
+    } else if qual.iter().any(|q| *q > 93) {
+        return Err(invalid_data("BAM record has a quality above 93"));
+    } else {
+        qual.iter().map(|q| q + 33).collect()
+    };
+
+    Ok(Record {
+        name,
+        flag: u16_at(14),
+        ref_id: i32_at(0),
+        pos: i32_at(4),
+        mapq: block[9],
+        cigar,
+        next_ref_id: i32_at(20),
+        next_pos: i32_at(24),
+        tlen: i32_at(28),
+        seq,
+        qual,
+        aux: Aux::Bam(block[aux_start..].to_vec()),
+    })
+}
+
+fn parse_sam_record(line: &str, header: &Header) -> Result<Record, Error> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 11 {
+        return Err(invalid_data(format!(
+            "SAM record has {} fields, expected at least 11",
+            fields.len()
+        )));
+    }
+    let number = |i: usize| {
+        fields[i]
+            .parse::<i64>()
+            .map_err(|_| invalid_data(format!("invalid SAM field {}: {}", i + 1, fields[i])))
+    };
+
+    let mut cigar = Vec::new();
+    if fields[5] != "*" {
+        let invalid = || invalid_data(format!("invalid CIGAR {}", fields[5]));
+        let mut length_opt = None;
+        for c in fields[5].bytes() {
+            if c.is_ascii_digit() {
+                let length = length_opt
+                    .unwrap_or(0u32)
+                    .checked_mul(10)
+                    .and_then(|length| length.checked_add((c - b'0') as u32))
+                    // BAM keeps lengths in 28 bits
+                    .filter(|length| *length < 1 << 28)
+                    .ok_or_else(invalid)?;
+                length_opt = Some(length);
+            } else {
+                match length_opt.take() {
+                    Some(length) if b"MIDNSHP=X".contains(&c) => cigar.push((length, c)),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        if length_opt.is_some() {
+            return Err(invalid());
+        }
+    }
+
+    let ref_id = header.reference_id(fields[2])?;
+    let next_ref_id = match fields[6] {
+        "=" => ref_id,
+        name => header.reference_id(name)?,
+    };
+    let seq = if fields[9] == "*" {
+        Vec::new()
+    } else {
+        fields[9].as_bytes().to_vec()
+    };
+    let qual = if fields[10] == "*" {
+        Vec::new()
+    } else {
+        fields[10].as_bytes().to_vec()
+    };
+
+    Ok(Record {
+        name: fields[0].to_owned(),
+        flag: number(1)? as u16,
+        ref_id,
+        pos: number(3)? as i32 - 1,
+        mapq: number(4)? as u8,
+        cigar,
+        next_ref_id,
+        next_pos: number(7)? as i32 - 1,
+        tlen: number(8)? as i32,
+        seq,
+        qual,
+        aux: Aux::Sam(fields[11..].iter().map(|field| field.to_string()).collect()),
+    })
+}
+
+/// Compresses into the blocked gzip format BAM files use. The end of file marker is written by
+/// `finish`, or when the writer is dropped, in which case errors are ignored.
+struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            finished: false,
+        }
+    }
+
+    /// Nothing can be written once the end of file marker is.
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.flush()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()
+    }
+
+    fn write_block(&mut self) -> Result<(), Error> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        let block_size = (compressed.len() + 25) as u16;
+        self.inner
+            .write_all(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0x00])?;
+        self.inner.write_all(b"BC\x02\x00")?;
+        self.inner.write_all(&block_size.to_le_bytes())?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner
+            .write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let length = std::cmp::min(buf.len(), BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..length]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+enum Output {
+    Sam(BufWriter<File>),
+    Bam(BgzfWriter<BufWriter<File>>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Output::Sam(writer) => writer.write(buf),
+            Output::Bam(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Output::Sam(writer) => writer.flush(),
+            Output::Bam(writer) => writer.flush(),
+        }
+    }
+}
+
+pub struct Writer {
+    inner: Output,
+    format: Format,
+    header: Header,
+}
+
+impl Writer {
+    pub fn to_file<P: AsRef<Path>>(
+        path: P,
+        header: &Header,
+        format: Format,
+    ) -> Result<Self, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut inner = match format {
+            Format::Sam => Output::Sam(file),
+            Format::Bam => Output::Bam(BgzfWriter::new(file)),
+        };
+        match format {
+            Format::Sam => inner.write_all(header.text.as_bytes())?,
+            Format::Bam => {
+                inner.write_all(BAM_MAGIC)?;
+                inner.write_all(&(header.text.len() as i32).to_le_bytes())?;
+                inner.write_all(header.text.as_bytes())?;
+                inner.write_all(&(header.references.len() as i32).to_le_bytes())?;
+                for (name, length) in header.references.iter() {
+                    inner.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+                    inner.write_all(name.as_bytes())?;
+                    inner.write_all(&[0])?;
+                    inner.write_all(&length.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(Writer {
+            inner,
+            format,
+            header: header.clone(),
+        })
+    }
+
+    /// Flushes the output, ending BAM with its end of file marker. Nothing can be written after.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            Output::Sam(writer) => writer.flush(),
+            Output::Bam(writer) => writer.finish(),
+        }
+    }
+
+    fn write_sam_record(&mut self, record: &Record) -> Result<(), Error> {
+        let cigar: String = if record.cigar.is_empty() {
+            "*".to_owned()
+        } else {
+            record
+                .cigar
+                .iter()
+                .map(|(length, op)| format!("{}{}", length, *op as char))
+                .collect()
+        };
+        let next_ref_name = if record.next_ref_id >= 0 && record.next_ref_id == record.ref_id {
+            "="
+        } else {
+            self.header.reference_name(record.next_ref_id)
+        };
+        let or_star = |bytes: &[u8]| {
+            if bytes.is_empty() {
+                "*".to_owned()
+            } else {
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+        };
+        write!(
+            self.inner,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            record.name,
+            record.flag,
+            self.header.reference_name(record.ref_id),
+            record.pos + 1,
+            record.mapq,
+            cigar,
+            next_ref_name,
+            record.next_pos + 1,
+            record.tlen,
+            or_star(&record.seq),
+            or_star(&record.qual),
+        )?;
+        let fields = match &record.aux {
+            Aux::Sam(fields) => Cow::Borrowed(fields),
+            Aux::Bam(aux) => Cow::Owned(bam_aux_to_sam(aux)?),
+        };
+        for field in fields.iter() {
+            write!(self.inner, "\t{}", field)?;
+        }
+        writeln!(self.inner)
+    }
+
+    fn write_bam_record(&mut self, record: &Record) -> Result<(), Error> {
+        let aux = match &record.aux {
+            Aux::Bam(aux) => Cow::Borrowed(aux),
+            Aux::Sam(fields) => Cow::Owned(sam_aux_to_bam(fields)?),
+        };
+        let mut block = Vec::with_capacity(64 + record.seq.len() * 2 + aux.len());
+        block.extend_from_slice(&record.ref_id.to_le_bytes());
+        block.extend_from_slice(&record.pos.to_le_bytes());
+        block.push(record.name.len() as u8 + 1);
+        block.push(record.mapq);
+        block.extend_from_slice(&record.bin().to_le_bytes());
+        block.extend_from_slice(&(record.cigar.len() as u16).to_le_bytes());
+        block.extend_from_slice(&record.flag.to_le_bytes());
+        block.extend_from_slice(&(record.seq.len() as i32).to_le_bytes());
+        block.extend_from_slice(&record.next_ref_id.to_le_bytes());
+        block.extend_from_slice(&record.next_pos.to_le_bytes());
+        block.extend_from_slice(&record.tlen.to_le_bytes());
+        block.extend_from_slice(record.name.as_bytes());
+        block.push(0);
+        for (length, op) in record.cigar.iter() {
+            let code = b"MIDNSHP=X".iter().position(|c| c == op).unwrap_or(0) as u32;
+            block.extend_from_slice(&((length << 4) | code).to_le_bytes());
+        }
+        for bases in record.seq.chunks(2) {
+            let code = |base: u8| {
+                SEQ_CODES
+                    .iter()
+                    .position(|c| *c == base.to_ascii_uppercase())
+                    .unwrap_or(15) as u8
+            };
+            let low = bases.get(1).map(|base| code(*base)).unwrap_or(0);
+            block.push((code(bases[0]) << 4) | low);
+        }
+        if record.qual.is_empty() {
+            block.extend(std::iter::repeat_n(0xff, record.seq.len()));
+        } else {
+            block.extend(record.qual.iter().map(|q| q.saturating_sub(33)));
+        }
+        block.extend_from_slice(&aux);
+
+        self.inner.write_all(&(block.len() as i32).to_le_bytes())?;
+        self.inner.write_all(&block)
+    }
+}
+
+impl Writer {
+    /// Records from other formats are written as unmapped reads, their description kept in the
+    /// `CO` optional field. Member `mate` of a pair or group of `mates` records is flagged as
+    /// the first, last or a middle segment of its template.
+    fn to_native<'a, R: fastx::Record>(
+        &self,
+        record: &'a R,
+        mate_opt: Option<(usize, usize)>,
+    ) -> Cow<'a, Record> {
+        if let Some(record) = record.as_any().downcast_ref::<Record>() {
+            return Cow::Borrowed(record);
+        }
+        let aux = match self.format {
+            Format::Sam => Aux::Sam(Vec::new()),
+            Format::Bam => Aux::Bam(Vec::new()),
+        };
+        let mut flag = FLAG_UNMAPPED;
+        if let Some((mate, mates)) = mate_opt.filter(|(_, mates)| *mates > 1) {
+            flag |= FLAG_PAIRED | FLAG_MATE_UNMAPPED;
+            if mate == 0 {
+                flag |= FLAG_FIRST;
+            }
+            if mate == mates - 1 {
+                flag |= FLAG_LAST;
+            }
+        }
+        let mut native = Record {
+            name: record.id().to_owned(),
+            flag,
+            ref_id: -1,
+            pos: -1,
+            mapq: 0,
+            cigar: Vec::new(),
+            next_ref_id: -1,
+            next_pos: -1,
+            tlen: 0,
+            seq: record.seq().to_vec(),
+            qual: record.qual().map(|qual| qual.to_vec()).unwrap_or_default(),
+            aux,
+        };
+        if let Some(desc) = record.desc() {
+            native.push_string_tag(b"CO", desc);
+        }
+        Cow::Owned(native)
+    }
+
+    fn write_native(&mut self, record: &Record) -> Result<(), Error> {
+        match self.format {
+            Format::Sam => self.write_sam_record(record),
+            Format::Bam => self.write_bam_record(record),
+        }
+    }
+
+    /// Duplicates are flagged with 0x400 like Picard MarkDuplicates.
+    fn write_marked_native(
+        &mut self,
+        record: Cow<Record>,
+        mark: &fastx::DuplicateMark,
+    ) -> Result<(), Error> {
+        let mut record = record.into_owned();
+        if mark.duplicate {
+            record.set_flag(record.flag() | FLAG_DUPLICATE);
+        } else {
+            record.set_flag(record.flag() & !FLAG_DUPLICATE);
+        }
+        self.write_native(&record)
+    }
+}
+
+impl<R: fastx::Record> fastx::Writer<R> for Writer {
+    fn write_record(&mut self, record: &R) -> Result<(), Error> {
+        let record = self.to_native(record, None);
+        self.write_native(&record)
+    }
+
+    /// SAM records have no description, so it is kept in the `CO` optional field.
+    fn write_record_with_desc(&mut self, record: &R, desc: Option<&str>) -> Result<(), Error> {
+        match desc {
+            Some(desc) => {
+                let mut record = self.to_native(record, None).into_owned();
+                record.push_string_tag(b"CO", desc);
+                self.write_native(&record)
+            }
+            None => self.write_record(record),
+        }
+    }
+
+    fn write_marked_record(
+        &mut self,
+        record: &R,
+        mark: &fastx::DuplicateMark,
+    ) -> Result<(), Error> {
+        let record = self.to_native(record, None);
+        self.write_marked_native(record, mark)
+    }
+
+    fn write_mate(&mut self, record: &R, mate: usize, mates: usize) -> Result<(), Error> {
+        let record = self.to_native(record, Some((mate, mates)));
+        self.write_native(&record)
+    }
+
+    fn write_marked_mate(
+        &mut self,
+        record: &R,
+        mate: usize,
+        mates: usize,
+        mark: &fastx::DuplicateMark,
+    ) -> Result<(), Error> {
+        let record = self.to_native(record, Some((mate, mates)));
+        self.write_marked_native(record, mark)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        Writer::finish(self)
+    }
+}
+
+impl format::Format for Format {
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Sam => "sam",
+            Format::Bam => "bam",
+        }
+    }
+
+    fn detect(&self, head: &[u8]) -> bool {
+        match self {
+            Format::Bam => head.starts_with(BAM_MAGIC),
+            // header lines are a two letter record type followed by a tab, e.g. @HD
+            Format::Sam => {
+                head.len() >= 4
+                    && head[0] == b'@'
+                    && head[1].is_ascii_uppercase()
+                    && head[2].is_ascii_uppercase()
+                    && head[3] == b'\t'
+            }
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Format::Sam => &["sam"],
+            Format::Bam => &["bam"],
+        }
+    }
+
+    /// Mates of paired reads are reordered so r1 comes first. Whether reads are paired is decided
+    /// by the first read, and the others must agree.
+    fn reader(&self, path: &Path) -> Result<format::Records, Error> {
+        let reader = Reader::from_file(path)?;
+        let tracker_opt = reader.tracker();
+        let mut records = reader.records().peekable();
+        let paired = match records.peek() {
+            Some(Ok(record)) => record.flag & FLAG_PAIRED != 0,
+            _ => false,
+        };
+        let records: format::Records = if paired {
+            Box::new(MatePairs::new(records).flat_map(|result| {
+                let mates: Vec<Result<Box<dyn fastx::Record>, Error>> = match result {
+                    Ok(pair) => {
+                        let (r1, r2) = pair.into();
+                        vec![Ok(Box::new(r1)), Ok(Box::new(r2))]
+                    }
+                    Err(err) => vec![Err(err)],
+                };
+                mates
+            }))
+        } else {
+            Box::new(records.map(|result| {
+                let record = result?;
+                if record.flag & FLAG_PAIRED != 0 {
+                    let message = format!(
+                        "read {} is flagged as paired but the first read is not, paired and \
+                         single-end reads can't be mixed",
+                        record.name
+                    );
+                    return Err(invalid_data(message));
+                }
+                Ok(Box::new(record) as Box<dyn fastx::Record>)
+            }))
+        };
+        Ok(Box::new(format::LocatedRecords::new(
+            records,
+            path,
+            tracker_opt,
+        )))
+    }
+
+    /// The header is copied from SAM/BAM inputs.
+    fn writer(
+        &self,
+        path: &Path,
+        input: &Path,
+        _options: &format::WriterOptions,
+    ) -> Result<format::RecordWriter, Error> {
+        let input_format_opt = format::detect(input).ok();
+        let header = match input_format_opt.map(|input_format| input_format.name()) {
+            Some("sam") | Some("bam") => Reader::from_file(input)?.header().clone(),
+            _ => Header::from_text("@HD\tVN:1.6\tSO:unsorted\n".to_owned()),
+        };
+        Ok(Box::new(Writer::to_file(path, &header, *self)?))
+    }
+
+    fn interleaved(&self, path: &Path) -> Result<bool, Error> {
+        is_paired(path)
+    }
+}
+
+/// Pairs consecutive mates of paired SAM/BAM records, using the first and last segment flags to
+/// tell r1 from r2. Each read must be followed by its mate, as in name sorted or collated files.
+pub struct MatePairs<R: Iterator<Item = Result<Record, Error>>> {
+    records: R,
+}
+
+impl<R: Iterator<Item = Result<Record, Error>>> MatePairs<R> {
+    pub fn new(records: R) -> Self {
+        MatePairs { records }
+    }
+}
+
+fn check_mate(mate: &Record) -> Result<(), Error> {
+    if mate.flag & FLAG_PAIRED == 0 || mate.flag & (FLAG_FIRST | FLAG_LAST) == 0 {
+        let message = format!(
+            "read {} is not flagged as a paired mate but the first read is, paired and \
+             single-end reads can't be mixed",
+            mate.name
+        );
+        return Err(invalid_data(message));
+    }
+    Ok(())
+}
+
+impl<R: Iterator<Item = Result<Record, Error>>> Iterator for MatePairs<R> {
+    type Item = Result<PairedRecord<Record>, Error>;
+
+    fn next(&mut self) -> Option<Result<PairedRecord<Record>, Error>> {
+        let mate_a = match self.records.next()? {
+            Ok(mate) => mate,
+            Err(err) => return Some(Err(err)),
+        };
+        if let Err(err) = check_mate(&mate_a) {
+            return Some(Err(err));
+        }
+        let mate_b = match self.records.next() {
+            None => {
+                let message = format!(
+                    "reached the end of input before the mate of {}",
+                    mate_a.name
+                );
+                return Some(Err(Error::new(ErrorKind::UnexpectedEof, message)));
+            }
+            Some(Err(err)) => return Some(Err(err)),
+            Some(Ok(mate)) => mate,
+        };
+        if let Err(err) = check_mate(&mate_b) {
+            return Some(Err(err));
+        }
+        if mate_a.name != mate_b.name {
+            let message = format!(
+                "read {} is followed by {} instead of its mate, paired input must be name sorted \
+                 or collated",
+                mate_a.name, mate_b.name
+            );
+            return Some(Err(invalid_data(message)));
+        }
+        let pair = if mate_a.flag & FLAG_FIRST != 0 {
+            (mate_a, mate_b)
+        } else {
+            (mate_b, mate_a)
+        };
+        Some(PairedRecord::try_from(pair))
+    }
+}
+
+/// Whether the first record of the file is flagged as paired, which we take to mean every record
+/// is.
+pub fn is_paired<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
+    Reader::from_file(path)?
+        .records()
+        .next()
+        .transpose()
+        .map(|record_opt| {
+            record_opt
+                .map(|record| record.flag & FLAG_PAIRED != 0)
+                .unwrap_or(false)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fastx::Record as _;
+    use crate::fastx::Writer as _;
+    use crate::paired::PairedWriter;
+    use std::convert::TryFrom;
+    use tempfile::tempdir;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:unsorted\n\
+        @RG\tID:rg1\tLB:lib1\n\
+        read1\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tRG:Z:rg1\tXN:i:7\n\
+        read1\t141\t*\t0\t0\t*\t*\t0\t0\tTTGA\tIIII\tRG:Z:rg1\tXA:A:c\tXD:i:-300\tXF:f:1.5\tXB:B:s,-2,300\n";
+
+    #[test]
+    fn test_sam_round_trip() {
+        let dir = tempdir().unwrap();
+        let input_path = dir.path().join("input.sam");
+        let output_path = dir.path().join("output.sam");
+        std::fs::write(&input_path, SAM).unwrap();
+
+        let reader = Reader::from_file(&input_path).expect("don't break");
+        assert_eq!(reader.format(), Format::Sam);
+        let header = reader.header().clone();
+        let records: Vec<Record> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "read1");
+        assert_eq!(records[1].seq(), b"TTGA");
+        assert_eq!(records[0].string_tag(b"RG"), Some("rg1".to_owned()));
+        {
+            let mut writer = Writer::to_file(&output_path, &header, Format::Sam).unwrap();
+            for record in records.iter() {
+                writer.write_record(record).unwrap();
+            }
+        }
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), SAM);
+    }
+
+    #[test]
+    fn test_malformed_bam_lengths() {
+        let mut block = vec![0; 36];
+        block[8] = 4;
+        block[16..20].copy_from_slice(&(-1i32).to_le_bytes());
+        assert!(parse_bam_record(&block).is_err());
+        block[16..20].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert!(parse_bam_record(&block).is_err());
+        // one base with a quality above 93
+        block[16..20].copy_from_slice(&1i32.to_le_bytes());
+        block[32..36].copy_from_slice(b"abc\0");
+        block.extend_from_slice(&[0x10, 200]);
+        assert!(parse_bam_record(&block).is_err());
+        block[37] = 30;
+        assert_eq!(parse_bam_record(&block).unwrap().qual, b"?");
+
+        let dir = tempdir().unwrap();
+        let bam_path = dir.path().join("input.bam");
+        {
+            let mut writer = BgzfWriter::new(File::create(&bam_path).unwrap());
+            writer.write_all(BAM_MAGIC).unwrap();
+            writer.write_all(&0i32.to_le_bytes()).unwrap();
+            writer.write_all(&0i32.to_le_bytes()).unwrap();
+            writer.write_all(&(-8i32).to_le_bytes()).unwrap();
+        }
+        let reader = Reader::from_file(&bam_path).expect("don't break");
+        assert!(reader.records().next().unwrap().is_err());
+
+        for header in [
+            &b"BAM\x01\xff\xff\xff\xff"[..],
+            &b"BAM\x01\x00\x00\x00\x7f"[..],
+        ]
+        .iter()
+        {
+            std::fs::write(&bam_path, header).unwrap();
+            let err = Reader::from_file(&bam_path).err().expect("don't break");
+            assert!(err.to_string().contains("the BAM header of"));
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_pairing() {
+        let dir = tempdir().unwrap();
+        let error = |reads: &str| {
+            let path = dir.path().join("input.sam");
+            std::fs::write(&path, format!("@HD\tVN:1.6\tSO:unsorted\n{}", reads)).unwrap();
+            let records = format::Format::reader(&Format::Sam, &path).expect("don't break");
+            records
+                .filter_map(|result| result.err())
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        let mixed = "read1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n\
+            read2\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n\
+            read2\t141\t*\t0\t0\t*\t*\t0\t0\tTTGA\tIIII\n";
+        assert!(error(mixed).contains("read read2 is flagged as paired"));
+
+        let mixed = "read1\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n\
+            read1\t141\t*\t0\t0\t*\t*\t0\t0\tTTGA\tIIII\n\
+            read2\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n";
+        assert!(error(mixed).contains("read read2 is not flagged as a paired mate"));
+
+        let unsorted = "read1\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n\
+            read2\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n\
+            read1\t141\t*\t0\t0\t*\t*\t0\t0\tTTGA\tIIII\n\
+            read2\t141\t*\t0\t0\t*\t*\t0\t0\tTTGA\tIIII\n";
+        assert!(error(unsorted).contains("read read1 is followed by read2 instead of its mate"));
+    }
+
+    #[test]
+    fn test_invalid_cigar() {
+        let header = Header::from_text("@SQ\tSN:chr1\tLN:100000\n".to_owned());
+        for cigar in ["99999999999M", "4Q", "2M2"].iter() {
+            let line = format!("read1\t0\tchr1\t1\t60\t{}\t*\t0\t0\tACGT\tIIII", cigar);
+            assert!(parse_sam_record(&line, &header).is_err());
+        }
+    }
+
+    #[test]
+    fn test_bin() {
+        let header = Header::from_text("@SQ\tSN:chr1\tLN:100000\n".to_owned());
+        let bin = |line: &str| parse_sam_record(line, &header).unwrap().bin();
+        assert_eq!(bin("read1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII"), 4680);
+        assert_eq!(
+            bin("read1\t0\tchr1\t101\t60\t4M\t*\t0\t0\tACGT\tIIII"),
+            4681
+        );
+        // spans 16383 to 16386, across the first 16kb boundary
+        assert_eq!(
+            bin("read1\t0\tchr1\t16384\t60\t2M1D1M\t*\t0\t0\tACG\tIII"),
+            585
+        );
+    }
+
+    #[test]
+    fn test_bam_round_trip() {
+        let dir = tempdir().unwrap();
+        let sam_path = dir.path().join("input.sam");
+        let bam_path = dir.path().join("input.bam");
+        let output_path = dir.path().join("output.sam");
+        std::fs::write(&sam_path, SAM).unwrap();
+
+        let reader = Reader::from_file(&sam_path).unwrap();
+        let header = reader.header().clone();
+        {
+            let mut writer = Writer::to_file(&bam_path, &header, Format::Bam).unwrap();
+            for record in reader.records() {
+                writer.write_record(&record.unwrap()).unwrap();
+            }
+            writer.finish().unwrap();
+            // finishing twice, as dropping the writer does, writes a single end of file marker
+            writer.finish().unwrap();
+        }
+        let bam = std::fs::read(&bam_path).unwrap();
+        let bam = bam
+            .strip_suffix(&BGZF_EOF[..])
+            .expect("ends with the marker");
+        assert!(!bam.ends_with(&BGZF_EOF));
+
+        let reader = Reader::from_file(&bam_path).expect("don't break");
+        assert_eq!(reader.format(), Format::Bam);
+        let header = reader.header().clone();
+        let pairs: Vec<PairedRecord<Record>> = MatePairs::new(reader.records())
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].r1().seq(), b"ACGT");
+        assert_eq!(pairs[0].r2().string_tag(b"RG"), Some("rg1".to_owned()));
+
+        {
+            let mut writer = Writer::to_file(&output_path, &header, Format::Sam).unwrap();
+            writer.write_record(pairs[0].r1()).unwrap();
+            writer.write_record(pairs[0].r2()).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), SAM);
+    }
+
+    #[test]
+    fn test_interleaved_pair_to_bam() {
+        let dir = tempdir().unwrap();
+        let bam_path = dir.path().join("output.bam");
+        let header = Header::from_text("@HD\tVN:1.6\tSO:unsorted\n".to_owned());
+        let pair = PairedRecord::try_from((
+            bio::io::fastq::Record::with_attrs("read1", None, b"ACGT", b"IIII"),
+            bio::io::fastq::Record::with_attrs("read1", None, b"TTGA", b"IIII"),
+        ))
+        .unwrap();
+        {
+            let writer = Writer::to_file(&bam_path, &header, Format::Bam).unwrap();
+            let mut writers = PairedWriter::new(writer, None);
+            writers.write_pair(&pair).unwrap();
+            writers.finish::<bio::io::fastq::Record>().unwrap();
+        }
+
+        let reader = Reader::from_file(&bam_path).expect("don't break");
+        let records: Vec<Record> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            records.iter().map(|record| record.flag).collect::<Vec<_>>(),
+            vec![
+                FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_FIRST,
+                FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_LAST,
+            ]
+        );
+        let pairs: Vec<PairedRecord<Record>> = MatePairs::new(records.into_iter().map(Ok))
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].r1().seq(), b"ACGT");
+        assert_eq!(pairs[0].r2().seq(), b"TTGA");
+    }
+}
@@ -0,0 +1,332 @@
+use flate2::write::GzEncoder;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Formats of the tables czid-dedup writes. JSON Lines objects are keyed on the column names, so
+/// the same names are used whatever the format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+    JsonLines,
+}
+
+impl FromStr for TableFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(TableFormat::Csv),
+            "tsv" => Ok(TableFormat::Tsv),
+            "jsonl" => Ok(TableFormat::JsonLines),
+            _ => Err(format!("table format {} is not one of csv, tsv, jsonl", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("compression {} is not one of none, gzip, zstd", s)),
+        }
+    }
+}
+
+enum Encoder {
+    Plain(io::BufWriter<File>),
+    Gzip(GzEncoder<io::BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, io::BufWriter<File>>),
+}
+
+/// A table file, compressed or not. Compressed streams are ended by `finish`, or when the file
+/// is dropped, in which case errors are ignored.
+pub struct TableFile {
+    encoder: Encoder,
+    finished: bool,
+}
+
+impl TableFile {
+    pub fn create<P: AsRef<Path>>(path: P, compression: Compression) -> io::Result<Self> {
+        let file = io::BufWriter::new(File::create(path)?);
+        let encoder = match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(TableFile {
+            encoder,
+            finished: false,
+        })
+    }
+
+    /// Nothing can be written once the file is finished.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        match &mut self.encoder {
+            Encoder::Plain(writer) => writer.flush(),
+            Encoder::Gzip(writer) => {
+                writer.try_finish()?;
+                writer.get_mut().flush()
+            }
+            Encoder::Zstd(writer) => {
+                writer.do_finish()?;
+                writer.get_mut().flush()
+            }
+        }
+    }
+}
+
+impl io::Write for TableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::Plain(writer) => writer.write(buf),
+            Encoder::Gzip(writer) => writer.write(buf),
+            Encoder::Zstd(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Plain(writer) => writer.flush(),
+            Encoder::Gzip(writer) => writer.flush(),
+            Encoder::Zstd(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Drop for TableFile {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Field<'a> {
+    Text(Cow<'a, str>),
+    Number(u64),
+}
+
+impl<'a> Field<'a> {
+    fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Field::Text(text) => Cow::Borrowed(text),
+            Field::Number(number) => Cow::Owned(number.to_string()),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Field<'a> {
+    fn from(text: &'a str) -> Self {
+        Field::Text(Cow::Borrowed(text))
+    }
+}
+
+fn write_json_string<W: io::Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+/// Quotes a CSV or TSV field if it holds the delimiter, a quote or a line break, as the `csv`
+/// crate does.
+fn write_delimited_field<W: io::Write>(writer: &mut W, delimiter: u8, s: &str) -> io::Result<()> {
+    let needs_quotes = s
+        .bytes()
+        .any(|byte| byte == delimiter || byte == b'"' || byte == b'\n' || byte == b'\r');
+    if needs_quotes {
+        writer.write_all(b"\"")?;
+        writer.write_all(s.replace('"', "\"\"").as_bytes())?;
+        writer.write_all(b"\"")
+    } else {
+        writer.write_all(s.as_bytes())
+    }
+}
+
+/// Writes a header then rows in any table format.
+pub struct TableWriter<W: io::Write> {
+    writer: io::BufWriter<W>,
+    format: TableFormat,
+    columns: Vec<String>,
+}
+
+impl<W: io::Write> TableWriter<W> {
+    pub fn new(writer: W, format: TableFormat) -> Self {
+        TableWriter {
+            writer: io::BufWriter::new(writer),
+            format,
+            columns: Vec::new(),
+        }
+    }
+
+    fn write_delimited(&mut self, delimiter: u8, fields: &[Field]) -> io::Result<()> {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(&[delimiter])?;
+            }
+            write_delimited_field(&mut self.writer, delimiter, &field.to_text())?;
+        }
+        self.writer.write_all(b"\n")
+    }
+
+    /// JSON Lines has no header line, the columns key every row instead.
+    pub fn write_header(&mut self, columns: &[&str]) -> io::Result<()> {
+        self.columns = columns.iter().map(|column| column.to_string()).collect();
+        let fields: Vec<Field> = columns.iter().map(|column| Field::from(*column)).collect();
+        match self.format {
+            TableFormat::Csv => self.write_delimited(b',', &fields),
+            TableFormat::Tsv => self.write_delimited(b'\t', &fields),
+            TableFormat::JsonLines => Ok(()),
+        }
+    }
+
+    pub fn write_row(&mut self, fields: &[Field]) -> io::Result<()> {
+        match self.format {
+            TableFormat::Csv => self.write_delimited(b',', fields),
+            TableFormat::Tsv => self.write_delimited(b'\t', fields),
+            TableFormat::JsonLines => {
+                let writer = &mut self.writer;
+                writer.write_all(b"{")?;
+                for (i, (column, field)) in self.columns.iter().zip(fields).enumerate() {
+                    if i > 0 {
+                        writer.write_all(b",")?;
+                    }
+                    write_json_string(writer, column)?;
+                    writer.write_all(b":")?;
+                    match field {
+                        Field::Text(text) => write_json_string(writer, text)?,
+                        Field::Number(number) => write!(writer, "{}", number)?,
+                    }
+                }
+                writer.write_all(b"}\n")
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The underlying writer, after everything written so far is flushed to it.
+    pub fn get_mut(&mut self) -> io::Result<&mut W> {
+        self.writer.flush()?;
+        Ok(self.writer.get_mut())
+    }
+}
+
+impl TableWriter<TableFile> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: TableFormat,
+        compression: Compression,
+    ) -> io::Result<Self> {
+        Ok(TableWriter::new(
+            TableFile::create(path, compression)?,
+            format,
+        ))
+    }
+
+    /// Flushes then finishes the file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.get_mut()?.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::str;
+
+    fn write_table(format: TableFormat) -> String {
+        let mut output = Vec::new();
+        {
+            let mut table_writer = TableWriter::new(&mut output, format);
+            table_writer
+                .write_header(&["read id", "cluster size"])
+                .expect("don't break");
+            table_writer
+                .write_row(&[Field::from("id_a,\"b\""), Field::Number(2)])
+                .expect("don't break");
+            table_writer.flush().expect("don't break");
+        }
+        str::from_utf8(&output).unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_table_writer() {
+        assert_eq!(
+            write_table(TableFormat::Csv),
+            "read id,cluster size\n\"id_a,\"\"b\"\"\",2\n"
+        );
+        assert_eq!(
+            write_table(TableFormat::Tsv),
+            "read id\tcluster size\n\"id_a,\"\"b\"\"\"\t2\n"
+        );
+        assert_eq!(
+            write_table(TableFormat::JsonLines),
+            "{\"read id\":\"id_a,\\\"b\\\"\",\"cluster size\":2}\n"
+        );
+        assert!(TableFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn test_table_file() {
+        let dir = tempfile::tempdir().expect("don't break");
+        for (compression, file_name) in [
+            (Compression::None, "sizes.tsv"),
+            (Compression::Gzip, "sizes.tsv.gz"),
+            (Compression::Zstd, "sizes.tsv.zst"),
+        ]
+        .iter()
+        {
+            let path = dir.path().join(file_name);
+            let mut table_writer =
+                TableWriter::create(&path, TableFormat::Tsv, *compression).expect("don't break");
+            table_writer
+                .write_header(&["representative read id", "cluster size"])
+                .expect("don't break");
+            table_writer
+                .write_row(&[Field::from("id_a"), Field::Number(2)])
+                .expect("don't break");
+            table_writer.finish().expect("don't break");
+            let mut contents = String::new();
+            crate::format::open(&path)
+                .expect("don't break")
+                .0
+                .read_to_string(&mut contents)
+                .expect("don't break");
+            assert_eq!(contents, "representative read id\tcluster size\nid_a\t2\n");
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::cmp;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Anchor {
+    Start,
+    End,
+}
+
+/// Trailing runs of any of `bases` at least `min_length` long, such as the poly-G two-colour
+/// chemistry reads when there is no signal.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PolyTrim {
+    bases: Vec<u8>,
+    min_length: usize,
+}
+
+impl PolyTrim {
+    pub fn new(bases: &[u8], min_length: usize) -> Self {
+        PolyTrim {
+            bases: bases.to_ascii_uppercase(),
+            min_length,
+        }
+    }
+
+    /// Runs are trimmed one after the other, so a poly-A tail followed by poly-G is trimmed too.
+    pub fn trim<'a>(&self, mut seq: &'a [u8]) -> &'a [u8] {
+        while let Some(last) = seq.last().map(u8::to_ascii_uppercase) {
+            if !self.bases.contains(&last) {
+                break;
+            }
+            let run = seq
+                .iter()
+                .rev()
+                .take_while(|base| base.to_ascii_uppercase() == last)
+                .count();
+            if run < self.min_length {
+                break;
+            }
+            seq = &seq[..seq.len() - run];
+        }
+        seq
+    }
+}
+
+/// The part of a read that is compared, `offset` bases in from its start or end, e.g.
+/// `start:6:50` skips 6 bases then takes 50 and `end:0:30` takes the last 30. A window without a
+/// length takes the rest of the read, and windows past the ends of short reads are truncated.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyWindow {
+    anchor: Anchor,
+    offset: usize,
+    length_opt: Option<usize>,
+    poly_trim_opt: Option<PolyTrim>,
+}
+
+impl KeyWindow {
+    /// The first `length` bases, or the whole read.
+    pub fn prefix(length_opt: Option<usize>) -> Self {
+        KeyWindow {
+            anchor: Anchor::Start,
+            offset: 0,
+            length_opt,
+            poly_trim_opt: None,
+        }
+    }
+
+    /// Trims trailing runs before the window is taken.
+    pub fn with_poly_trim(mut self, poly_trim: PolyTrim) -> Self {
+        self.poly_trim_opt = Some(poly_trim);
+        self
+    }
+
+    /// A window of length zero leaves its mate out of the key.
+    pub fn ignores_mate(&self) -> bool {
+        self.length_opt == Some(0)
+    }
+
+    pub fn extract<'a>(&self, seq: &'a [u8]) -> &'a [u8] {
+        let seq = match self.poly_trim_opt.as_ref() {
+            Some(poly_trim) => poly_trim.trim(seq),
+            None => seq,
+        };
+        let (start, end) = match self.anchor {
+            Anchor::Start => {
+                let start = cmp::min(self.offset, seq.len());
+                let end = self
+                    .length_opt
+                    .map(|length| cmp::min(start + length, seq.len()))
+                    .unwrap_or_else(|| seq.len());
+                (start, end)
+            }
+            Anchor::End => {
+                let end = seq.len().saturating_sub(self.offset);
+                let start = self
+                    .length_opt
+                    .map(|length| end.saturating_sub(length))
+                    .unwrap_or(0);
+                (start, end)
+            }
+        };
+        &seq[start..end]
+    }
+}
+
+impl FromStr for KeyWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid key window {}: {}", s, reason);
+        let mut fields = s.split(':');
+        // split always yields a first field
+        let anchor = match fields.next().unwrap() {
+            "start" => Anchor::Start,
+            "end" => Anchor::End,
+            anchor => return Err(invalid(&format!("{} is not one of start, end", anchor))),
+        };
+        let offset = match fields.next() {
+            Some(offset) => offset
+                .parse::<usize>()
+                .map_err(|_| invalid(&format!("offset {} is not a number", offset)))?,
+            None => return Err(invalid("expected an offset")),
+        };
+        let length_opt = match fields.next() {
+            Some("") | None => None,
+            Some(length) => Some(
+                length
+                    .parse::<usize>()
+                    .map_err(|_| invalid(&format!("length {} is not a number", length)))?,
+            ),
+        };
+        if fields.next().is_some() {
+            return Err(invalid("expected at most three fields"));
+        }
+        Ok(KeyWindow {
+            anchor,
+            offset,
+            length_opt,
+            poly_trim_opt: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let window = |s: &str| KeyWindow::from_str(s).expect("don't break");
+        assert_eq!(window("start:2:3").extract(b"AACGTTT"), b"CGT");
+        assert_eq!(window("start:2").extract(b"AACGTTT"), b"CGTTT");
+        assert_eq!(window("start:5:10").extract(b"AACGTTT"), b"TT");
+        assert_eq!(window("end:0:3").extract(b"AACGTTT"), b"TTT");
+        assert_eq!(window("end:2:").extract(b"AACGTTT"), b"AACGT");
+        assert_eq!(window("end:9:2").extract(b"AACGTTT"), b"");
+        assert_eq!(KeyWindow::prefix(Some(2)).extract(b"AACGTTT"), b"AA");
+        assert!(KeyWindow::from_str("middle:2").is_err());
+        assert!(KeyWindow::from_str("start").is_err());
+        assert!(KeyWindow::from_str("start:1:2:3").is_err());
+    }
+
+    #[test]
+    fn test_poly_trim() {
+        let poly_trim = PolyTrim::new(b"GA", 3);
+        assert_eq!(poly_trim.trim(b"ACGTaaaGGGG"), b"ACGT");
+        assert_eq!(poly_trim.trim(b"ACGTAAGGG"), b"ACGTAA");
+        assert_eq!(poly_trim.trim(b"GGGG"), b"");
+        let window = KeyWindow::from_str("end:0:2")
+            .expect("don't break")
+            .with_poly_trim(poly_trim);
+        assert_eq!(window.extract(b"ACGTCCGGGGGG"), b"CC");
+    }
+}